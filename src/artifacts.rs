@@ -0,0 +1,206 @@
+//! Images kept in the output directory after each benchmark, and the disk space they need.
+//!
+//! A full run copies the raw image for every method, so the space needed grows with the number of jobs. It is
+//! checked before the first benchmark, instead of failing halfway through.
+
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, bail};
+use byte_unit::{Byte, UnitType};
+use clap::ValueEnum;
+use hashbrown::HashMap;
+use nix::sys::statvfs::statvfs;
+
+/// Images kept in the output directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, ValueEnum)]
+pub enum KeepArtifacts {
+    /// No images, not even the raw ones, once the run is over.
+    None,
+    /// The raw images and the smallest compressed image of each preset and target.
+    Best,
+    /// All raw, compressed and decompressed images.
+    #[default]
+    All,
+}
+
+/// Space in the output directory for one job, from the size of its raw image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JobSpace<K> {
+    /// Preset and target, sharing a raw image.
+    pub key: K,
+    /// Size of the raw image, in bytes.
+    pub raw: u64,
+}
+
+impl KeepArtifacts {
+    /// Estimate the disk space needed by `jobs` in the output directory.
+    ///
+    /// Compressed images are assumed as large as the raw image, since initramfs contents are often compressed
+    /// already. With `in_place`, the decompressed copy is written to the output directory too.
+    #[must_use]
+    pub fn required_space<K: Eq + std::hash::Hash>(self, jobs: &[JobSpace<K>], in_place: bool) -> u64 {
+        let copies = if in_place { 2 } else { 1 };
+        let footprint = |job: &JobSpace<K>| job.raw.saturating_mul(copies);
+        let largest = jobs.iter().map(footprint).max().unwrap_or_default();
+
+        match self {
+            Self::All => jobs.iter().map(footprint).fold(0, u64::saturating_add),
+            Self::Best => {
+                let mut best = HashMap::new();
+                for job in jobs {
+                    best.insert(&job.key, job.raw);
+                }
+                best.values().fold(largest, |total, raw| total.saturating_add(*raw))
+            }
+            Self::None => largest,
+        }
+    }
+}
+
+/// Free space for unprivileged users on the filesystem of `path`, in bytes.
+///
+/// # Errors
+///
+/// Could not query the filesystem.
+pub fn free_space(path: &Path) -> Result<u64> {
+    let stats = statvfs(path).with_context(|| format!("could not query free space of {}", path.display()))?;
+    Ok(stats.blocks_available().saturating_mul(stats.fragment_size()))
+}
+
+/// Check that `required` bytes fit in the free space of `outdir`.
+///
+/// # Errors
+///
+/// Not enough free space, or could not query the filesystem.
+pub fn check_space(outdir: &Path, required: u64) -> Result<()> {
+    let available = free_space(outdir)?;
+    let display = |bytes| Byte::from_u64(bytes).get_appropriate_unit(UnitType::Binary);
+    log::info!("disk space: required={:.1}, available={:.1}", display(required), display(available));
+    if required > available {
+        bail!(
+            "not enough disk space in {}: {:.1} required, {:.1} available, try `--keep-artifacts` or `--workdir`",
+            outdir.display(),
+            display(required),
+            display(available)
+        );
+    }
+    Ok(())
+}
+
+/// Images left by the jobs, removed according to [`KeepArtifacts`].
+#[derive(Debug)]
+pub struct Artifacts<K> {
+    /// What to keep.
+    keep: KeepArtifacts,
+    /// Smallest compressed image so far, for each preset and target.
+    best: HashMap<K, (Byte, PathBuf)>,
+}
+
+impl<K: Eq + std::hash::Hash> Artifacts<K> {
+    /// Nothing kept yet.
+    #[must_use]
+    pub fn new(keep: KeepArtifacts) -> Self {
+        Self {
+            keep,
+            best: HashMap::new(),
+        }
+    }
+
+    /// Keep or remove the `decompressed` and `compressed` images of a finished job.
+    ///
+    /// `compressed_size` is the size of a successful compression.
+    pub fn retain(&mut self, key: K, decompressed: &Path, compressed: &Path, compressed_size: Option<Byte>) {
+        if self.keep == KeepArtifacts::All {
+            return;
+        }
+        remove(decompressed);
+
+        match (self.keep, compressed_size) {
+            (KeepArtifacts::Best, Some(size)) => {
+                let previous = self.best.get(&key);
+                if previous.is_some_and(|(best, _)| *best <= size) {
+                    remove(compressed);
+                } else if let Some((_, path)) = self.best.insert(key, (size, compressed.to_path_buf())) {
+                    remove(&path);
+                }
+            }
+            (KeepArtifacts::Best | KeepArtifacts::None, _) => remove(compressed),
+            (KeepArtifacts::All, _) => (),
+        }
+    }
+
+    /// Remove the raw `images` if nothing is kept, once all jobs are finished.
+    pub fn finish<'a>(self, images: impl IntoIterator<Item = &'a Path>) {
+        if self.keep == KeepArtifacts::None {
+            images.into_iter().for_each(remove);
+        }
+    }
+}
+
+/// Remove a file, if it exists, only reporting errors.
+fn remove(path: &Path) {
+    match std::fs::remove_file(path) {
+        Ok(()) => log::debug!("artifacts: removed {}", path.display()),
+        Err(error) if error.kind() == ErrorKind::NotFound => (),
+        Err(error) => log::warn!("artifacts: could not remove {}: {error}", path.display()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use test_log::test;
+
+    use super::*;
+
+    #[test]
+    fn estimates_space() {
+        let jobs = [
+            JobSpace { key: "img", raw: 100 },
+            JobSpace { key: "img", raw: 100 },
+            JobSpace { key: "uki", raw: 300 },
+        ];
+        assert_eq!(KeepArtifacts::All.required_space(&jobs, true), 1000);
+        assert_eq!(KeepArtifacts::All.required_space(&jobs, false), 500);
+        assert_eq!(KeepArtifacts::Best.required_space(&jobs, true), 1000, "largest job and one image per key");
+        assert_eq!(KeepArtifacts::None.required_space(&jobs, false), 300);
+        assert_eq!(KeepArtifacts::None.required_space(&[] as &[JobSpace<&str>], true), 0);
+
+        let dir = tempfile::tempdir().unwrap();
+        assert!(free_space(dir.path()).unwrap() > 0, "some free space");
+        assert!(check_space(dir.path(), u64::MAX).is_err(), "never enough");
+    }
+
+    #[test]
+    fn keeps_best_images() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = |name: &str| {
+            let path = dir.path().join(name);
+            std::fs::write(&path, name).unwrap();
+            path
+        };
+        let raw = file("test.img");
+        let mut artifacts = Artifacts::new(KeepArtifacts::Best);
+
+        let (first, large) = (file("test.img.0"), file("test.img.0.zst"));
+        artifacts.retain("img", &first, &large, Some(Byte::from_u64(200)));
+        let (second, small) = (file("test.img.1"), file("test.img.1.xz"));
+        artifacts.retain("img", &second, &small, Some(Byte::from_u64(100)));
+        let (third, failed) = (file("test.img.2"), file("test.img.2.lz4"));
+        artifacts.retain("img", &third, &failed, None);
+        artifacts.finish([raw.as_path()]);
+
+        let mut kept: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        kept.sort_unstable();
+        assert_eq!(kept, ["test.img", "test.img.1.xz"]);
+
+        let mut none = Artifacts::new(KeepArtifacts::None);
+        none.retain("img", &second, &small, Some(Byte::from_u64(100)));
+        none.finish([raw.as_path()]);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0, "nothing kept");
+    }
+}
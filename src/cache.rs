@@ -0,0 +1,120 @@
+//! Page cache state before each measured command.
+
+use std::fmt;
+use std::fs::File;
+use std::os::fd::AsRawFd;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use nix::fcntl::{PosixFadviseAdvice, posix_fadvise};
+
+/// Kernel interface to drop clean caches, see [proc_sys_vm(5)](https://man.archlinux.org/man/proc_sys_vm.5).
+const DROP_CACHES: &str = "/proc/sys/vm/drop_caches";
+
+/// How the page cache is prepared before each compression and decompression.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, ValueEnum)]
+pub enum CacheMode {
+    /// Inputs are read before the command, so they are served from memory.
+    #[default]
+    Warm,
+    /// Inputs and outputs are evicted from the page cache with `posix_fadvise`.
+    ColdFile,
+    /// All clean caches are dropped before the command, requires root.
+    ColdGlobal,
+}
+
+impl CacheMode {
+    /// Prepare the page cache for a command reading `input`.
+    ///
+    /// # Errors
+    ///
+    /// Could not read or evict the file, or drop the caches.
+    pub fn prepare(self, input: &Path) -> Result<()> {
+        log::trace!("cache: mode={self}, input={}", input.display());
+        match self {
+            Self::Warm => {
+                let mut file = open(input)?;
+                std::io::copy(&mut file, &mut std::io::sink())
+                    .with_context(|| format!("could not read {}", input.display()))?;
+            }
+            Self::ColdFile => evict(input)?,
+            Self::ColdGlobal => {
+                nix::unistd::sync();
+                std::fs::write(DROP_CACHES, "3").with_context(|| format!("could not write to {DROP_CACHES}"))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Clean up after a command that wrote `output`.
+    ///
+    /// Only evicts the output on [`CacheMode::ColdFile`], so it does not stay in memory for the next commands.
+    ///
+    /// # Errors
+    ///
+    /// Could not flush or evict the file.
+    pub fn release(self, output: &Path) -> Result<()> {
+        match self {
+            Self::ColdFile => evict(output),
+            Self::Warm | Self::ColdGlobal => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for CacheMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Warm => "warm",
+            Self::ColdFile => "cold-file",
+            Self::ColdGlobal => "cold-global",
+        })
+    }
+}
+
+/// Open a file with context.
+fn open(path: &Path) -> Result<File> {
+    File::open(path).with_context(|| format!("could not open {}", path.display()))
+}
+
+/// Flush a file and drop its pages from the page cache.
+///
+/// Dirty pages can't be dropped, so they are written first.
+fn evict(path: &Path) -> Result<()> {
+    let file = open(path)?;
+    file.sync_data()
+        .with_context(|| format!("could not flush {}", path.display()))?;
+    posix_fadvise(file.as_raw_fd(), 0, 0, PosixFadviseAdvice::POSIX_FADV_DONTNEED)
+        .with_context(|| format!("could not evict {}", path.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use test_log::test;
+
+    use super::*;
+
+    #[test]
+    fn prepares_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("image");
+        std::fs::write(&path, vec![0_u8; 1 << 16]).unwrap();
+
+        CacheMode::Warm.prepare(&path).unwrap();
+        CacheMode::ColdFile.prepare(&path).unwrap();
+        CacheMode::ColdFile.release(&path).unwrap();
+
+        let error = CacheMode::ColdFile.prepare(&dir.path().join("missing")).unwrap_err();
+        assert!(error.to_string().starts_with("could not open "), "{error}");
+    }
+
+    #[test]
+    fn displays_like_cli() {
+        for mode in CacheMode::value_variants() {
+            let value = mode.to_possible_value().unwrap();
+            assert_eq!(value.get_name(), mode.to_string());
+        }
+    }
+}
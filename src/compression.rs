@@ -0,0 +1,346 @@
+//! Compression methods to be tested.
+
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::fs::File;
+use std::num::NonZeroU32;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+
+use crate::measure::{CpuList, Options, Schedule, Stats};
+use crate::tools::Tool;
+
+/// A compression method to be tested.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Compression {
+    /// Unique method name.
+    pub name: &'static str,
+    /// Extension for compressed file.
+    pub extension: &'static str,
+    /// Kernel option required to decompress the initramfs at boot.
+    pub kernel_option: &'static str,
+    /// Binary used for compression and decompression.
+    pub tool: &'static str,
+    /// Argument setting the number of compression threads, if the tool is multithreaded.
+    ///
+    /// Multithreaded tools always get a count, one thread per usable CPU by default, since their own default may ignore
+    /// the CPU affinity, like `-T0` of `zstd`. Decompression uses the default of the tool, which is multithreaded for
+    /// `pbzip2`, and for `xz` since 5.4.
+    pub threads_arg: Option<ThreadsArg>,
+    /// Arguments to compress a file, before the file path, keeping the original with `-k`.
+    pub compress_args: &'static [&'static str],
+    /// Arguments to decompress a file, before the file path, keeping the original with `-k`.
+    pub decompress_args: &'static [&'static str],
+}
+
+impl Compression {
+    /// Tool can compress with multiple threads.
+    #[inline]
+    #[must_use]
+    pub const fn is_multithreaded(&self) -> bool {
+        self.threads_arg.is_some()
+    }
+
+    /// Number of threads used to compress on `cpus` usable CPUs, for parallel efficiency.
+    #[must_use]
+    pub fn threads(&self, threads: Option<Threads>, cpus: NonZeroU32) -> NonZeroU32 {
+        if self.is_multithreaded() {
+            threads.unwrap_or(Threads::All).get(cpus)
+        } else {
+            NonZeroU32::MIN
+        }
+    }
+
+    /// Compress a file, keeping the original.
+    ///
+    /// Multithreaded tools use the given number of `threads`, or one per usable CPU when `None`.
+    ///
+    /// # Errors
+    ///
+    /// Command failed to run or exited with non-zero status.
+    pub fn compress(&self, tool: &Tool, path: &Path, threads: Option<Threads>, options: &Options) -> Result<Stats> {
+        let args = self.compress_args_with(threads, usable_cpus(&options.schedule));
+        options.exec(&tool.path, args.into_iter().chain([path.into()]))
+    }
+
+    /// Arguments to compress a file with some number of threads on `cpus` usable CPUs, before the file path.
+    fn compress_args_with(&self, threads: Option<Threads>, cpus: NonZeroU32) -> Vec<OsString> {
+        let mut args: Vec<OsString> = self.compress_args.iter().map(OsString::from).collect();
+        if let Some(threads_arg) = self.threads_arg {
+            let count = self.threads(threads, cpus);
+            match threads_arg {
+                ThreadsArg::Joined(prefix) => args.push(format!("{prefix}{count}").into()),
+                ThreadsArg::Separate(option) => args.extend([option.into(), count.to_string().into()]),
+            }
+        }
+        args
+    }
+
+    /// Decompress a file, keeping the original.
+    ///
+    /// # Errors
+    ///
+    /// Command failed to run or exited with non-zero status.
+    pub fn decompress(&self, tool: &Tool, path: &Path, options: &Options) -> Result<Stats> {
+        options.exec(&tool.path, self.decompress_args.iter().map(OsStr::new).chain([path.as_os_str()]))
+    }
+
+    /// Compress `input` into `output`, through standard input and output.
+    ///
+    /// For files the tool can't name the output after, like memfds. The tool doesn't know the input size.
+    ///
+    /// # Errors
+    ///
+    /// Could not open the files, or command failed to run or exited with non-zero status.
+    pub fn compress_stream(
+        &self,
+        tool: &Tool,
+        input: &Path,
+        output: &Path,
+        threads: Option<Threads>,
+        options: &Options,
+    ) -> Result<Stats> {
+        let args = self
+            .compress_args_with(threads, usable_cpus(&options.schedule))
+            .into_iter()
+            .chain([STDOUT_ARG.into()]);
+        options.exec_with_stdio(&tool.path, args, open(input)?, create(output)?)
+    }
+
+    /// Decompress `input` into `output`, through standard input and output.
+    ///
+    /// # Errors
+    ///
+    /// Could not open the files, or command failed to run or exited with non-zero status.
+    pub fn decompress_stream(&self, tool: &Tool, input: &Path, output: &Path, options: &Options) -> Result<Stats> {
+        let args = self.decompress_args.iter().copied().chain([STDOUT_ARG]);
+        options.exec_with_stdio(&tool.path, args, open(input)?, create(output)?)
+    }
+}
+
+/// How a multithreaded tool takes its number of threads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ThreadsArg {
+    /// Prefix of the count in the same argument, like `-T` for `-T4`.
+    Joined(&'static str),
+    /// Option followed by the count in the next argument, like `-p` for `-p 4`.
+    Separate(&'static str),
+}
+
+/// Argument writing the output to stdout, shared by all tools.
+const STDOUT_ARG: &str = "-c";
+
+/// Open a file for reading, with context.
+fn open(path: &Path) -> Result<File> {
+    File::open(path).with_context(|| format!("could not open {}", path.display()))
+}
+
+/// Create or truncate a file for writing, with context.
+fn create(path: &Path) -> Result<File> {
+    File::create(path).with_context(|| format!("could not create {}", path.display()))
+}
+
+/// List of compression methods to test.
+pub const COMPRESSION: &[Compression] = &[
+    Compression {
+        name: "lz4-fast",
+        extension: ".lz4",
+        kernel_option: "CONFIG_RD_LZ4",
+        tool: "lz4",
+        threads_arg: None,
+        compress_args: &["-v", "-k", "-12"],
+        decompress_args: &["-v", "-d", "-k"],
+    },
+    Compression {
+        name: "lz4-norm",
+        extension: ".lz4",
+        kernel_option: "CONFIG_RD_LZ4",
+        tool: "lz4",
+        threads_arg: None,
+        compress_args: &["-v", "-k"],
+        decompress_args: &["-v", "-d", "-k"],
+    },
+    Compression {
+        name: "lz4-high",
+        extension: ".lz4",
+        kernel_option: "CONFIG_RD_LZ4",
+        tool: "lz4",
+        threads_arg: None,
+        compress_args: &["-v", "-k", "--fast=12"],
+        decompress_args: &["-v", "-d", "-k"],
+    },
+    Compression {
+        name: "zstd-fast",
+        extension: ".zst",
+        kernel_option: "CONFIG_RD_ZSTD",
+        tool: "zstdmt",
+        threads_arg: Some(ThreadsArg::Joined("-T")),
+        compress_args: &["-v", "-k", "-1"],
+        decompress_args: &["-v", "-d", "-k"],
+    },
+    Compression {
+        name: "zstd-norm",
+        extension: ".zst",
+        kernel_option: "CONFIG_RD_ZSTD",
+        tool: "zstdmt",
+        threads_arg: Some(ThreadsArg::Joined("-T")),
+        compress_args: &["-v", "-k", "-5", "--long"],
+        decompress_args: &["-v", "-d", "-k"],
+    },
+    Compression {
+        name: "zstd-high",
+        extension: ".zst",
+        kernel_option: "CONFIG_RD_ZSTD",
+        tool: "zstdmt",
+        threads_arg: Some(ThreadsArg::Joined("-T")),
+        compress_args: &["-v", "-k", "-19", "--long"],
+        decompress_args: &["-v", "-d", "-k"],
+    },
+    Compression {
+        name: "xz-norm",
+        extension: ".xz",
+        kernel_option: "CONFIG_RD_XZ",
+        tool: "xz",
+        threads_arg: Some(ThreadsArg::Joined("-T")),
+        compress_args: &["-v", "-k", "--check=crc32", "-6"],
+        decompress_args: &["-v", "-d", "-k"],
+    },
+    Compression {
+        name: "gzip-norm",
+        extension: ".gz",
+        kernel_option: "CONFIG_RD_GZIP",
+        tool: "pigz",
+        threads_arg: Some(ThreadsArg::Separate("-p")),
+        compress_args: &["-v", "-k", "-9"],
+        decompress_args: &["-v", "-d", "-k"],
+    },
+    Compression {
+        name: "bzip2-norm",
+        extension: ".bz2",
+        kernel_option: "CONFIG_RD_BZIP2",
+        tool: "pbzip2",
+        threads_arg: Some(ThreadsArg::Joined("-p")),
+        compress_args: &["-v", "-k", "-9"],
+        decompress_args: &["-v", "-d", "-k"],
+    },
+];
+
+/// Number of compression threads, in a `--threads` sweep.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Threads {
+    /// One thread per usable CPU.
+    All,
+    /// A fixed number of threads.
+    Count(NonZeroU32),
+}
+
+impl Threads {
+    /// Actual number of threads, with `cpus` usable CPUs.
+    #[must_use]
+    pub const fn get(self, cpus: NonZeroU32) -> NonZeroU32 {
+        match self {
+            Self::All => cpus,
+            Self::Count(count) => count,
+        }
+    }
+}
+
+impl FromStr for Threads {
+    type Err = anyhow::Error;
+
+    fn from_str(threads: &str) -> Result<Self> {
+        if threads == "all" {
+            return Ok(Self::All);
+        }
+        let count = threads
+            .parse()
+            .with_context(|| format!("expected a positive number of threads or `all`: {threads:?}"))?;
+        Ok(Self::Count(count))
+    }
+}
+
+impl fmt::Display for Threads {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::All => f.write_str("all"),
+            Self::Count(count) => write!(f, "{count}"),
+        }
+    }
+}
+
+/// Binaries used by all compression methods, without repetition.
+#[must_use]
+pub fn required_tools() -> Vec<&'static str> {
+    let mut tools: Vec<_> = COMPRESSION.iter().map(|compression| compression.tool).collect();
+    tools.sort_unstable();
+    tools.dedup();
+    tools
+}
+
+/// Number of CPUs this process may run on.
+#[must_use]
+pub fn available_cpus() -> NonZeroU32 {
+    std::thread::available_parallelism()
+        .ok()
+        .and_then(|cpus| u32::try_from(cpus.get()).ok())
+        .and_then(NonZeroU32::new)
+        .unwrap_or(NonZeroU32::MIN)
+}
+
+/// Number of CPUs measured commands may run on, from the CPU affinity of the `schedule` or of this process.
+#[must_use]
+pub fn usable_cpus(schedule: &Schedule) -> NonZeroU32 {
+    schedule.cpus.as_ref().map_or_else(available_cpus, CpuList::count)
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use test_log::test;
+
+    use super::*;
+
+    #[test]
+    fn parses_threads() {
+        assert_eq!("all".parse::<Threads>().unwrap(), Threads::All);
+        assert_eq!("4".parse::<Threads>().unwrap().to_string(), "4");
+        assert!("0".parse::<Threads>().is_err(), "zero threads");
+        assert!("many".parse::<Threads>().is_err(), "not a number");
+    }
+
+    #[test]
+    fn sets_thread_count() {
+        let find = |name: &str| COMPRESSION.iter().find(|compression| compression.name == name).unwrap();
+        let four = Some(Threads::Count(NonZeroU32::new(4).unwrap()));
+
+        let cpus = NonZeroU32::new(2).unwrap();
+
+        let xz = find("xz-norm");
+        assert_eq!(xz.compress_args_with(None, cpus), ["-v", "-k", "--check=crc32", "-6", "-T2"], "usable CPUs");
+        assert_eq!(xz.compress_args_with(four, cpus).last().unwrap(), "-T4");
+        assert_eq!(xz.compress_args_with(Some(Threads::All), cpus).last().unwrap(), "-T2");
+        assert_eq!(xz.threads(four, cpus), NonZeroU32::new(4).unwrap());
+        assert_eq!(xz.threads(None, cpus), cpus, "one thread per usable CPU");
+        assert_eq!(find("gzip-norm").compress_args_with(four, cpus)[3..], ["-p", "4"], "pigz takes no joined value");
+        assert_eq!(find("bzip2-norm").compress_args_with(four, cpus).last().unwrap(), "-p4");
+
+        let lz4 = find("lz4-norm");
+        assert_eq!(lz4.compress_args_with(four, cpus), ["-v", "-k"], "single-threaded");
+        assert_eq!(lz4.threads(four, cpus), NonZeroU32::MIN);
+
+        let schedule = Schedule {
+            cpus: Some("0,2-3".parse().unwrap()),
+            ..Schedule::default()
+        };
+        assert_eq!(usable_cpus(&schedule), NonZeroU32::new(3).unwrap());
+        assert!(
+            COMPRESSION
+                .iter()
+                .all(|compression| compression.compress_args.contains(&"-k")
+                    && compression.decompress_args.contains(&"-k")),
+            "originals kept"
+        );
+    }
+}
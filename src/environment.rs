@@ -0,0 +1,430 @@
+//! Readings of system noise that may disturb the measurements.
+//!
+//! A busy, throttled or battery-powered machine gives slower and more variable results, so these readings are
+//! checked before each benchmark and kept with the results.
+//!
+//! The first reading is taken on the idle machine, before the run. The previous benchmark loads the machine itself,
+//! so each benchmark then waits for the readings to come back near that baseline.
+
+use std::fmt;
+use std::num::NonZeroU32;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// Load average per CPU above which the machine is considered busy.
+const MAX_LOAD_PER_CPU: f64 = 0.25;
+/// Share of time stalled, in percent over the last 10 seconds, above which there is resource pressure.
+const MAX_PRESSURE: f64 = 5.0;
+/// Temperature, in degrees Celsius, above which the CPU may be throttled.
+const MAX_TEMPERATURE: f64 = 80.0;
+/// Governor that keeps the CPU frequency stable.
+const PERFORMANCE_GOVERNOR: &str = "performance";
+/// Interval between readings while waiting for the machine to settle.
+const SETTLE_INTERVAL: Duration = Duration::from_secs(1);
+/// Longest wait for the machine to settle after a benchmark, enough for `avg10` pressure to decay.
+const SETTLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// System load average, from `/proc/loadavg`.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub struct LoadAverage {
+    /// Average over the last minute.
+    pub one: f64,
+    /// Average over the last 5 minutes.
+    pub five: f64,
+    /// Average over the last 15 minutes.
+    pub fifteen: f64,
+    /// Tasks runnable when read, including the reader.
+    pub running: u32,
+}
+
+impl LoadAverage {
+    /// Parse the contents of `/proc/loadavg`.
+    fn parse(content: &str) -> Result<Self> {
+        let mut fields = content.split_whitespace().map(str::parse::<f64>);
+        let mut next = || {
+            fields
+                .next()
+                .context("missing field")?
+                .with_context(|| format!("invalid load average: {content:?}"))
+        };
+        let (one, five, fifteen) = (next()?, next()?, next()?);
+        let running = content
+            .split_whitespace()
+            .nth(3)
+            .and_then(|tasks| tasks.split_once('/')?.0.parse().ok())
+            .with_context(|| format!("invalid running tasks: {content:?}"))?;
+        Ok(Self {
+            one,
+            five,
+            fifteen,
+            running,
+        })
+    }
+}
+
+/// Pressure stall information for a resource, see [PSI](https://docs.kernel.org/accounting/psi.html).
+///
+/// Values are the share of time, in percent, where some task was stalled waiting for the resource.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub struct Pressure {
+    /// Average over the last 10 seconds.
+    pub avg10: f64,
+    /// Average over the last 60 seconds.
+    pub avg60: f64,
+}
+
+impl Pressure {
+    /// Parse the `some` line from a `/proc/pressure` file.
+    fn parse(content: &str) -> Option<Self> {
+        let line = content.lines().find_map(|line| line.strip_prefix("some "))?;
+        let value = |key: &str| {
+            line.split_whitespace()
+                .find_map(|field| field.strip_prefix(key)?.strip_prefix('='))
+                .and_then(|value| value.parse().ok())
+        };
+        Some(Self {
+            avg10: value("avg10")?,
+            avg60: value("avg60")?,
+        })
+    }
+}
+
+/// Temperature of a thermal zone, from `/sys/class/thermal`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ThermalZone {
+    /// Zone type, like `x86_pkg_temp`.
+    pub name: String,
+    /// Temperature in degrees Celsius.
+    pub temperature: f64,
+}
+
+/// Readings of the system state at some point of the run.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct Environment {
+    /// Load average, if readable.
+    pub load: Option<LoadAverage>,
+    /// CPU pressure, if the kernel has PSI.
+    pub cpu_pressure: Option<Pressure>,
+    /// I/O pressure, if the kernel has PSI.
+    pub io_pressure: Option<Pressure>,
+    /// Memory pressure, if the kernel has PSI.
+    pub memory_pressure: Option<Pressure>,
+    /// Frequency governors in use, without repetition.
+    pub governors: Vec<String>,
+    /// Temperature of each thermal zone.
+    pub thermal: Vec<ThermalZone>,
+    /// Running on battery, if there is a battery.
+    pub on_battery: Option<bool>,
+}
+
+impl Environment {
+    /// Read the current state of the system.
+    ///
+    /// Missing interfaces are left empty, since they depend on the kernel and hardware.
+    #[must_use]
+    pub fn read() -> Self {
+        Self::read_from(Path::new("/"))
+    }
+
+    /// Read the state from `/proc` and `/sys` under `root`.
+    fn read_from(root: &Path) -> Self {
+        let read = |path: &Path| {
+            std::fs::read_to_string(root.join(path))
+                .inspect_err(|error| log::trace!("environment: path={}, error={error}", path.display()))
+                .ok()
+        };
+        let pressure =
+            |resource: &str| read(&Path::new("proc/pressure").join(resource)).and_then(|psi| Pressure::parse(&psi));
+
+        let mut governors: Vec<_> = list_dir(&root.join("sys/devices/system/cpu"))
+            .filter(|name| name.strip_prefix("cpu").is_some_and(|id| id.parse::<u32>().is_ok()))
+            .filter_map(|cpu| {
+                read(
+                    &Path::new("sys/devices/system/cpu")
+                        .join(cpu)
+                        .join("cpufreq/scaling_governor"),
+                )
+            })
+            .map(|governor| governor.trim().to_owned())
+            .collect();
+        governors.sort_unstable();
+        governors.dedup();
+
+        let thermal = list_dir(&root.join("sys/class/thermal"))
+            .filter(|name| name.starts_with("thermal_zone"))
+            .filter_map(|zone| {
+                let dir = Path::new("sys/class/thermal").join(&zone);
+                let millidegrees: f64 = read(&dir.join("temp"))?.trim().parse().ok()?;
+                Some(ThermalZone {
+                    name: read(&dir.join("type")).map_or_else(|| zone.clone(), |name| name.trim().to_owned()),
+                    temperature: millidegrees / 1000.0,
+                })
+            })
+            .collect();
+
+        let batteries: Vec<_> = list_dir(&root.join("sys/class/power_supply"))
+            .map(|supply| Path::new("sys/class/power_supply").join(supply))
+            .filter(|dir| read(&dir.join("type")).is_some_and(|kind| kind.trim() == "Battery"))
+            .collect();
+        let on_battery = (!batteries.is_empty()).then(|| {
+            batteries
+                .iter()
+                .any(|dir| read(&dir.join("status")).is_some_and(|status| status.trim() == "Discharging"))
+        });
+
+        Self {
+            load: read(Path::new("proc/loadavg")).and_then(|load| LoadAverage::parse(&load).ok()),
+            cpu_pressure: pressure("cpu"),
+            io_pressure: pressure("io"),
+            memory_pressure: pressure("memory"),
+            governors,
+            thermal,
+            on_battery,
+        }
+    }
+
+    /// Wait for the readings that change with the load to come back near the `baseline`, on a machine with `cpus`
+    /// available CPUs.
+    ///
+    /// Returns the last readings, with the conditions still found after a timeout.
+    #[must_use]
+    pub fn settle(baseline: &Self, cpus: NonZeroU32) -> (Self, Vec<String>) {
+        Self::settle_from(Path::new("/"), baseline, cpus, SETTLE_TIMEOUT)
+    }
+
+    /// Wait up to `timeout` for the readings under `root` to come back near the `baseline`.
+    fn settle_from(root: &Path, baseline: &Self, cpus: NonZeroU32, timeout: Duration) -> (Self, Vec<String>) {
+        let start_time = Instant::now();
+        loop {
+            let environment = Self::read_from(root);
+            let issues = environment.issues_since(baseline, cpus);
+            if issues.is_empty() || start_time.elapsed() >= timeout {
+                log::debug!("environment: settled={}, waited {:?}", issues.is_empty(), start_time.elapsed());
+                return (environment, issues);
+            }
+            std::thread::sleep(SETTLE_INTERVAL);
+        }
+    }
+
+    /// Conditions that may disturb the measurements, on an idle machine with `cpus` available CPUs.
+    #[must_use]
+    pub fn issues(&self, cpus: NonZeroU32) -> Vec<String> {
+        let mut issues = Vec::new();
+        if let Some(load) = self.load
+            && load.one > MAX_LOAD_PER_CPU * f64::from(cpus.get())
+        {
+            issues.push(format!("load average is {:.2} on {cpus} CPUs", load.one));
+        }
+        for (resource, pressure) in self.pressures() {
+            if let Some(pressure) = pressure
+                && pressure.avg10 > MAX_PRESSURE
+            {
+                issues.push(format!("{resource} pressure is {:.1}% over the last 10s", pressure.avg10));
+            }
+        }
+        self.push_state_issues(&mut issues);
+        issues
+    }
+
+    /// Conditions that change with the load and may disturb the measurements, compared to the `baseline` taken before
+    /// the run.
+    ///
+    /// The load average lags for minutes behind the previous benchmark, so running tasks are compared instead.
+    /// Pressure is compared to the baseline. Governor, temperature and power supply don't settle between benchmarks,
+    /// so they are only checked once, with [`issues`](Self::issues).
+    #[must_use]
+    pub fn issues_since(&self, baseline: &Self, cpus: NonZeroU32) -> Vec<String> {
+        let mut issues = Vec::new();
+        if let (Some(load), Some(idle)) = (self.load, baseline.load)
+            && f64::from(load.running) > MAX_LOAD_PER_CPU.mul_add(f64::from(cpus.get()), f64::from(idle.running))
+        {
+            issues.push(format!("{} tasks running, {} before the run", load.running, idle.running));
+        }
+        for ((resource, pressure), (_, idle)) in self.pressures().into_iter().zip(baseline.pressures()) {
+            if let (Some(pressure), Some(idle)) = (pressure, idle)
+                && pressure.avg10 > idle.avg10 + MAX_PRESSURE
+            {
+                issues.push(format!(
+                    "{resource} pressure is {:.1}% over the last 10s, {:.1}% before the run",
+                    pressure.avg10, idle.avg10
+                ));
+            }
+        }
+        issues
+    }
+
+    /// Pressure of each resource, with its name.
+    const fn pressures(&self) -> [(&'static str, Option<Pressure>); 3] {
+        [
+            ("CPU", self.cpu_pressure),
+            ("I/O", self.io_pressure),
+            ("memory", self.memory_pressure),
+        ]
+    }
+
+    /// Add conditions that don't depend on the load: governor, temperature and power supply.
+    fn push_state_issues(&self, issues: &mut Vec<String>) {
+        for governor in &self.governors {
+            if governor != PERFORMANCE_GOVERNOR {
+                issues.push(format!("CPU frequency governor is {governor}, not {PERFORMANCE_GOVERNOR}"));
+            }
+        }
+        for zone in &self.thermal {
+            if zone.temperature > MAX_TEMPERATURE {
+                issues.push(format!("thermal zone {} is at {:.1}°C", zone.name, zone.temperature));
+            }
+        }
+        if self.on_battery == Some(true) {
+            issues.push("running on battery".into());
+        }
+    }
+}
+
+impl fmt::Display for Environment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.load {
+            Some(load) => write!(f, "load={:.2}/{:.2}/{:.2}", load.one, load.five, load.fifteen)?,
+            None => f.write_str("load=unknown")?,
+        }
+        for (resource, pressure) in [
+            ("cpu", self.cpu_pressure),
+            ("io", self.io_pressure),
+            ("memory", self.memory_pressure),
+        ] {
+            if let Some(pressure) = pressure {
+                write!(f, ", psi.{resource}={:.1}%", pressure.avg10)?;
+            }
+        }
+        if !self.governors.is_empty() {
+            write!(f, ", governor={}", self.governors.join("+"))?;
+        }
+        if let Some(hottest) = self
+            .thermal
+            .iter()
+            .max_by(|a, b| a.temperature.total_cmp(&b.temperature))
+        {
+            write!(f, ", thermal={:.1}°C ({})", hottest.temperature, hottest.name)?;
+        }
+        match self.on_battery {
+            Some(true) => f.write_str(", power=battery"),
+            Some(false) => f.write_str(", power=ac"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Names of the entries of a directory, ignoring errors.
+fn list_dir(dir: &Path) -> impl Iterator<Item = String> {
+    let mut names: Vec<_> = std::fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .collect();
+    names.sort_unstable();
+    names.into_iter()
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use test_log::test;
+
+    use super::*;
+
+    #[test]
+    fn parses_proc_files() {
+        let load = LoadAverage::parse("0.52 0.58 0.59 1/467 12345\n").unwrap();
+        assert_eq!(
+            load,
+            LoadAverage {
+                one: 0.52,
+                five: 0.58,
+                fifteen: 0.59,
+                running: 1
+            }
+        );
+        assert!(LoadAverage::parse("").is_err(), "empty file");
+        assert!(LoadAverage::parse("0.52 0.58 0.59\n").is_err(), "no running tasks");
+
+        let pressure = Pressure::parse(
+            "some avg10=1.53 avg60=0.87 avg300=0.20 total=123\nfull avg10=0.00 avg60=0.00 avg300=0.00 total=0\n",
+        );
+        assert_eq!(
+            pressure,
+            Some(Pressure {
+                avg10: 1.53,
+                avg60: 0.87
+            })
+        );
+        assert_eq!(Pressure::parse("full avg10=0.00 avg60=0.00 avg300=0.00 total=0\n"), None);
+    }
+
+    #[test]
+    fn reads_and_checks_sysfs() {
+        let root = tempfile::tempdir().unwrap();
+        let write = |path: &str, content: &str| {
+            let path = root.path().join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        };
+        write("proc/loadavg", "3.00 1.00 0.50 2/100 999\n");
+        write("proc/pressure/io", "some avg10=12.00 avg60=3.00 avg300=1.00 total=5\n");
+        write("sys/devices/system/cpu/cpu0/cpufreq/scaling_governor", "powersave\n");
+        write("sys/devices/system/cpu/cpu1/cpufreq/scaling_governor", "powersave\n");
+        write("sys/devices/system/cpu/cpufreq/boost", "1\n");
+        write("sys/class/thermal/thermal_zone0/type", "x86_pkg_temp\n");
+        write("sys/class/thermal/thermal_zone0/temp", "91500\n");
+        write("sys/class/power_supply/BAT0/type", "Battery\n");
+        write("sys/class/power_supply/BAT0/status", "Discharging\n");
+
+        let environment = Environment::read_from(root.path());
+        assert_eq!(environment.governors, ["powersave"]);
+        assert_eq!(environment.on_battery, Some(true));
+        assert_eq!(environment.cpu_pressure, None, "no PSI for CPU");
+        assert_eq!(
+            environment.to_string(),
+            "load=3.00/1.00/0.50, psi.io=12.0%, governor=powersave, thermal=91.5°C (x86_pkg_temp), power=battery"
+        );
+        assert_eq!(
+            environment.issues(NonZeroU32::new(4).unwrap()),
+            [
+                "load average is 3.00 on 4 CPUs",
+                "I/O pressure is 12.0% over the last 10s",
+                "CPU frequency governor is powersave, not performance",
+                "thermal zone x86_pkg_temp is at 91.5°C",
+                "running on battery",
+            ]
+        );
+
+        let baseline = Environment {
+            load: Some(LoadAverage {
+                running: 1,
+                ..LoadAverage::default()
+            }),
+            io_pressure: Some(Pressure::default()),
+            ..Environment::default()
+        };
+        assert_eq!(
+            environment.issues_since(&baseline, NonZeroU32::MIN),
+            [
+                "2 tasks running, 1 before the run",
+                "I/O pressure is 12.0% over the last 10s, 0.0% before the run",
+            ]
+        );
+
+        let (settled, issues) = Environment::settle_from(root.path(), &baseline, NonZeroU32::MIN, Duration::ZERO);
+        assert_eq!(settled, environment);
+        assert_eq!(issues.len(), 2, "gave up at once");
+        let start_time = Instant::now();
+        let (_, issues) = Environment::settle_from(root.path(), &environment, NonZeroU32::MIN, SETTLE_TIMEOUT);
+        assert!(issues.is_empty(), "governor, temperature and battery don't settle");
+        assert!(start_time.elapsed() < SETTLE_INTERVAL, "no wait");
+
+        let quiet = Environment::read_from(&root.path().join("missing"));
+        assert_eq!(quiet, Environment::default());
+        assert!(quiet.issues(NonZeroU32::MIN).is_empty(), "nothing to check");
+    }
+}
@@ -0,0 +1,423 @@
+//! History of the results of all runs, to see what moved between them.
+//!
+//! Each run appends one line to `history.jsonl` in the output directory, so older results survive the removal of
+//! their run directories.
+
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::{Context, Result, bail};
+use byte_unit::{Byte, UnitType};
+use serde::{Deserialize, Serialize};
+
+use crate::environment::Environment;
+use crate::plan::Job;
+use crate::report::{self, Benchmark};
+use crate::stats::{Comparison, Outliers, Test};
+
+/// Name of the history file in the output directory.
+pub const HISTORY_FILE: &str = "history.jsonl";
+
+/// Measurements of a single benchmark.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    /// Preset name.
+    pub preset: String,
+    /// Method name, with the number of threads.
+    pub method: String,
+    /// Compressed image, `img` or `uki`.
+    pub target: String,
+    /// Repetition number, when repeating the benchmark.
+    pub repetition: Option<u32>,
+    /// Size of the raw image, in bytes.
+    pub original_size: u64,
+    /// Size of the compressed image, in bytes.
+    pub compressed_size: u64,
+    /// Real time of the compression, in seconds.
+    pub compress_time: f64,
+    /// Real time of the decompression, in seconds.
+    pub decompress_time: f64,
+    /// Noise readings before the benchmark, missing in older histories.
+    #[serde(default)]
+    pub environment: Environment,
+}
+
+impl Record {
+    /// Measurements of the `job` for `preset`.
+    #[must_use]
+    pub fn new(job: &Job, preset: &str, benchmark: &Benchmark) -> Self {
+        Self {
+            preset: preset.to_owned(),
+            method: job.method(),
+            target: job.target.to_string(),
+            repetition: job.repetition,
+            original_size: benchmark.original_size.as_u64(),
+            compressed_size: benchmark.compressed_size.as_u64(),
+            compress_time: benchmark.compress.real_time().as_secs_f64(),
+            decompress_time: benchmark.decompress.real_time().as_secs_f64(),
+            environment: benchmark.environment.clone(),
+        }
+    }
+
+    /// Identity of the measured scenario, the same across runs.
+    fn key(&self) -> (String, String, String) {
+        (self.preset.clone(), self.method.clone(), self.target.clone())
+    }
+}
+
+/// Results of a run.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Run {
+    /// Name of the run directory, like `20250101T120000Z`.
+    pub id: String,
+    /// When the run started, in RFC 3339.
+    pub started: String,
+    /// Seed for the order of the benchmarks.
+    pub seed: u64,
+    /// Successful benchmarks.
+    pub results: Vec<Record>,
+}
+
+impl Run {
+    /// Append to the history file in `outdir`.
+    ///
+    /// # Errors
+    ///
+    /// Could not serialize or write the file.
+    pub fn append_to(&self, outdir: &Path) -> Result<()> {
+        let path = outdir.join(HISTORY_FILE);
+        let line = serde_json::to_string(self).context("could not serialize results")?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("could not open {}", path.display()))?;
+        file.write_all((line + "\n").as_bytes())
+            .with_context(|| format!("could not write {}", path.display()))
+    }
+}
+
+/// All runs in the history file of `outdir`, oldest first.
+///
+/// Lines that can't be read, like one cut short by a crash, are skipped with a warning.
+///
+/// # Errors
+///
+/// Could not read the file.
+pub fn load(outdir: &Path) -> Result<Vec<Run>> {
+    let path = outdir.join(HISTORY_FILE);
+    let content = std::fs::read_to_string(&path).with_context(|| format!("could not read {}", path.display()))?;
+    let runs = content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .filter_map(|(index, line)| {
+            serde_json::from_str(line)
+                .inspect_err(|error| log::warn!("{}:{}: skipped, {error}", path.display(), index + 1))
+                .ok()
+        })
+        .collect();
+    Ok(runs)
+}
+
+/// Pick the runs to compare, by id, defaulting to the last two.
+///
+/// # Errors
+///
+/// Unknown id, or not enough runs.
+pub fn select<'a>(runs: &'a [Run], old: Option<&str>, new: Option<&str>) -> Result<(&'a Run, &'a Run)> {
+    let find = |id: &str| {
+        runs.iter()
+            .rfind(|run| run.id == id)
+            .with_context(|| format!("no run {id} in the history"))
+    };
+    match (old, new) {
+        (Some(old), Some(new)) => Ok((find(old)?, find(new)?)),
+        (Some(old), None) => match runs.last() {
+            Some(last) => Ok((find(old)?, last)),
+            None => bail!("no runs in the history"),
+        },
+        (None, _) => match runs {
+            [.., old, new] => Ok((old, new)),
+            [] | [_] => bail!("at least two runs are needed to compare, found {}", runs.len()),
+        },
+    }
+}
+
+/// Median results of a scenario over its repetitions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Summary {
+    /// Size of the compressed image, in bytes.
+    pub compressed_size: u64,
+    /// Size of the raw image divided by the compressed size.
+    pub ratio: f64,
+    /// Real time of the compression, in seconds.
+    pub compress_time: f64,
+    /// Real time of the decompression, in seconds.
+    pub decompress_time: f64,
+}
+
+impl Summary {
+    /// Medians of `records`, if any, without the excluded `outliers` of the times.
+    fn of(records: &[&Record], outliers: Outliers) -> Option<Self> {
+        let original_size = median(records.iter().map(|record| record.original_size), u64::cmp)?;
+        let compressed_size = median(records.iter().map(|record| record.compressed_size), u64::cmp)?;
+        let time = |field: fn(&Record) -> f64| {
+            let sample: Vec<_> = records.iter().map(|record| field(record)).collect();
+            median(outliers.filter(&sample).into_iter(), f64::total_cmp)
+        };
+        Some(Self {
+            compressed_size,
+            ratio: as_f64(original_size) / as_f64(compressed_size),
+            compress_time: time(|record| record.compress_time)?,
+            decompress_time: time(|record| record.decompress_time)?,
+        })
+    }
+}
+
+/// Change of a scenario between two runs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Delta {
+    /// Preset name.
+    pub preset: String,
+    /// Method name, with the number of threads.
+    pub method: String,
+    /// Compressed image.
+    pub target: String,
+    /// Results in the older run, if measured.
+    pub old: Option<Summary>,
+    /// Results in the newer run, if measured.
+    pub new: Option<Summary>,
+    /// Test of the compression times, if both runs were repeated enough for it.
+    pub compress: Option<Comparison>,
+    /// Test of the decompression times, if both runs were repeated enough for it.
+    pub decompress: Option<Comparison>,
+}
+
+/// Changes of all scenarios from `old` to `new`, ordered by preset, method and target.
+///
+/// Time changes are checked with `test` over the repetitions of each run, and summarized by their medians, both without
+/// the excluded `outliers`.
+#[must_use]
+pub fn compare(old: &Run, new: &Run, test: Test, outliers: Outliers) -> Vec<Delta> {
+    let (old, mut new) = (group(old), group(new));
+
+    let mut deltas: Vec<_> = old
+        .into_iter()
+        .map(|(key, records)| {
+            let newer = new.remove(&key);
+            (key, Some(records), newer)
+        })
+        .collect();
+    deltas.extend(new.into_iter().map(|(key, records)| (key, None, Some(records))));
+    deltas.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+
+    deltas
+        .into_iter()
+        .map(|((preset, method, target), old, new)| {
+            let (old, new) = (old.unwrap_or_default(), new.unwrap_or_default());
+            let time = |field: fn(&Record) -> f64| {
+                let sample = |records: &[&Record]| {
+                    outliers.filter(&records.iter().map(|record| field(record)).collect::<Vec<_>>())
+                };
+                Comparison::new(test, &sample(&old), &sample(&new))
+            };
+            Delta {
+                preset,
+                method,
+                target,
+                compress: time(|record| record.compress_time),
+                decompress: time(|record| record.decompress_time),
+                old: Summary::of(&old, outliers),
+                new: Summary::of(&new, outliers),
+            }
+        })
+        .collect()
+}
+
+impl fmt::Display for Delta {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}/{}: ", self.preset, self.method, self.target)?;
+        let (old, new) = match (self.old, self.new) {
+            (Some(old), Some(new)) => (old, new),
+            (Some(_), None) => return f.write_str("only in the older run"),
+            (None, Some(_)) => return f.write_str("only in the newer run"),
+            (None, None) => return f.write_str("no results"),
+        };
+
+        let size = |bytes| Byte::from_u64(bytes).get_appropriate_unit(UnitType::Binary);
+        write!(
+            f,
+            "size={:.2} -> {:.2} ({}), ratio={:.3} -> {:.3} ({}), compress={:.3}s -> {:.3}s ({}), \
+             decompress={:.3}s -> {:.3}s ({})",
+            size(old.compressed_size),
+            size(new.compressed_size),
+            Change(as_f64(old.compressed_size), as_f64(new.compressed_size), None),
+            old.ratio,
+            new.ratio,
+            Change(old.ratio, new.ratio, None),
+            old.compress_time,
+            new.compress_time,
+            Change(old.compress_time, new.compress_time, self.compress),
+            old.decompress_time,
+            new.decompress_time,
+            Change(old.decompress_time, new.decompress_time, self.decompress),
+        )
+    }
+}
+
+/// Percentage change from an old to a new value, with its significance if tested.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Change(f64, f64, Option<Comparison>);
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Self(old, new, comparison) = *self;
+        if old > 0.0 {
+            write!(f, "{:+.1}%", (new - old) / old * 100.0)?;
+        } else {
+            f.write_str("n/a")?;
+        }
+        match comparison {
+            Some(comparison) if !comparison.is_significant() => {
+                write!(f, ", not significant, p={:.3}", comparison.p_value)
+            }
+            Some(_) | None => Ok(()),
+        }
+    }
+}
+
+/// Records of a run, by scenario.
+fn group(run: &Run) -> BTreeMap<(String, String, String), Vec<&Record>> {
+    let mut groups: BTreeMap<_, Vec<_>> = BTreeMap::new();
+    for record in &run.results {
+        groups.entry(record.key()).or_default().push(record);
+    }
+    groups
+}
+
+/// Median of some values ordered by `compare`, the lower one for an even count.
+fn median<T: Copy>(values: impl Iterator<Item = T>, compare: impl FnMut(&T, &T) -> Ordering) -> Option<T> {
+    let mut values: Vec<_> = values.collect();
+    values.sort_unstable_by(compare);
+    values.get(values.len().div_ceil(2).saturating_sub(1)).copied()
+}
+
+/// Number of bytes, for ratios.
+fn as_f64(bytes: u64) -> f64 {
+    report::as_f64(Byte::from_u64(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use test_log::test;
+
+    use super::*;
+
+    /// Record of a scenario with round numbers.
+    fn record(method: &str, compressed_size: u64, compress_time: f64) -> Record {
+        Record {
+            preset: "linux".into(),
+            method: method.into(),
+            target: "img".into(),
+            repetition: None,
+            original_size: 1000,
+            compressed_size,
+            compress_time,
+            decompress_time: 0.5,
+            environment: Environment::default(),
+        }
+    }
+
+    #[test]
+    fn appends_and_loads_runs() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(load(dir.path()).is_err(), "no history yet");
+
+        let run = |id: &str, results| Run {
+            id: id.into(),
+            started: "2025-01-01T12:00:00Z".into(),
+            seed: 42,
+            results,
+        };
+        let first = run("20250101T120000Z", vec![record("zstd", 500, 1.0)]);
+        let second = run("20250102T120000Z", vec![record("zstd", 400, 2.0)]);
+        first.append_to(dir.path()).unwrap();
+        std::fs::write(
+            dir.path().join(HISTORY_FILE),
+            std::fs::read_to_string(dir.path().join(HISTORY_FILE)).unwrap() + "{\"id\":\n",
+        )
+        .unwrap();
+        second.append_to(dir.path()).unwrap();
+
+        let runs = load(dir.path()).unwrap();
+        assert_eq!(runs, [first.clone(), second.clone()], "broken line skipped");
+        assert_eq!(select(&runs, None, None).unwrap(), (&first, &second));
+        assert_eq!(select(&runs, Some("20250102T120000Z"), Some("20250101T120000Z")).unwrap(), (&second, &first));
+        assert!(select(&runs, Some("20250103T120000Z"), None).is_err(), "unknown run");
+        assert!(select(&runs[..1], None, None).is_err(), "single run");
+
+        let mut older = serde_json::to_value(record("zstd", 500, 1.0)).unwrap();
+        older.as_object_mut().unwrap().remove("environment");
+        let older: Record = serde_json::from_value(older).unwrap();
+        assert_eq!(older.environment, Environment::default(), "history without readings");
+    }
+
+    #[test]
+    fn summarizes_without_outliers() {
+        let records: Vec<_> = [1.0, 2.0, 3.0, 4.0, 100.0].map(|time| record("zstd", 500, time)).into();
+        let records: Vec<_> = records.iter().collect();
+        let kept = Outliers::default();
+        assert_eq!(Summary::of(&records, kept).map(|summary| summary.compress_time), Some(3.0), "all repetitions");
+        let excluded = Outliers { exclude: true, ..kept };
+        assert_eq!(
+            Summary::of(&records, excluded).map(|summary| summary.compress_time),
+            Some(2.0),
+            "lower median of 4"
+        );
+        assert_eq!(Summary::of(&[], excluded), None);
+    }
+
+    #[test]
+    fn compares_runs() {
+        let old = Run {
+            id: "old".into(),
+            started: String::new(),
+            seed: 1,
+            results: vec![
+                record("lz4", 800, 0.1),
+                record("zstd", 500, 1.0),
+                record("zstd", 500, 3.0),
+                record("zstd", 600, 2.0),
+            ],
+        };
+        let new = Run {
+            id: "new".into(),
+            results: vec![
+                record("xz", 300, 4.0),
+                record("zstd", 400, 1.5),
+                record("zstd", 400, 2.5),
+                record("zstd", 400, 2.2),
+            ],
+            ..old.clone()
+        };
+
+        let deltas = compare(&old, &new, Test::Welch, Outliers::default());
+        let lines: Vec<_> = deltas.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            lines,
+            [
+                "linux/lz4/img: only in the older run",
+                "linux/xz/img: only in the newer run",
+                "linux/zstd/img: size=500 B -> 400 B (-20.0%), ratio=2.000 -> 2.500 (+25.0%), \
+                 compress=2.000s -> 2.200s (+10.0%, not significant, p=0.925), \
+                 decompress=0.500s -> 0.500s (+0.0%, not significant, p=1.000)",
+            ]
+        );
+    }
+}
@@ -0,0 +1,171 @@
+//! Reading the build configuration of a kernel.
+
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use flate2::read::GzDecoder;
+use hashbrown::HashMap;
+
+use super::release::running_release;
+
+/// Build configuration (`.config`) of a kernel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KernelConfig {
+    /// Kernel release for this configuration.
+    release: Box<str>,
+    /// File where the configuration was read from.
+    path: PathBuf,
+    /// List of `CONFIG_*=value` options.
+    options: HashMap<Box<str>, Box<str>>,
+}
+
+impl KernelConfig {
+    /// Find and load the configuration for a kernel release.
+    ///
+    /// Searches `/proc/config.gz` (only for the running kernel), `/usr/lib/modules/<release>/build/.config` and
+    /// `/boot/config-<release>`, in that order. Returns [`None`] if none of them exist.
+    ///
+    /// # Errors
+    ///
+    /// Configuration file exists, but could not be read.
+    pub fn load(release: &str) -> Result<Option<Self>> {
+        let mut candidates = Vec::with_capacity(3);
+        if running_release().is_ok_and(|running| *running == *release) {
+            candidates.push(PathBuf::from("/proc/config.gz"));
+        }
+        candidates.push(Path::new("/usr/lib/modules").join(release).join("build/.config"));
+        candidates.push(Path::new("/boot").join(format!("config-{release}")));
+
+        for path in candidates {
+            log::trace!("KernelConfig::load: release={release}, path={}, exists={}", path.display(), path.exists());
+            if path.exists() {
+                let text = read_config(&path).with_context(|| format!("could not read {}", path.display()))?;
+                let options = parse_options(&text);
+                log::debug!(
+                    "KernelConfig::load: release={release}, path={}, #options={}",
+                    path.display(),
+                    options.len()
+                );
+                return Ok(Some(Self {
+                    release: release.into(),
+                    path,
+                    options,
+                }));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Kernel release for this configuration.
+    #[inline]
+    #[must_use]
+    pub const fn release(&self) -> &str {
+        &self.release
+    }
+
+    /// File where the configuration was read from.
+    #[inline]
+    #[must_use]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Check if an option was built in or as a module.
+    #[must_use]
+    pub fn is_enabled(&self, option: &str) -> bool {
+        matches!(self.options.get(option).map(AsRef::as_ref), Some("y" | "m"))
+    }
+}
+
+/// Read a configuration file, decompressing it if needed.
+fn read_config(path: &Path) -> Result<String> {
+    let data = std::fs::read(path)?;
+    if path.extension() == Some("gz".as_ref()) {
+        let mut text = String::new();
+        GzDecoder::new(data.as_slice()).read_to_string(&mut text)?;
+        Ok(text)
+    } else {
+        Ok(String::from_utf8(data)?)
+    }
+}
+
+/// Parse `CONFIG_*=value` lines, ignoring comments.
+///
+/// Options marked as `# CONFIG_* is not set` are left out.
+fn parse_options(text: &str) -> HashMap<Box<str>, Box<str>> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(name, value)| (name.trim().into(), value.trim().into()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+    use pretty_assertions::assert_eq;
+    use tempfile::TempDir;
+    use test_log::test;
+
+    use super::*;
+
+    const EXAMPLE: &str = "
+#
+# Automatically generated file; DO NOT EDIT.
+# Linux/x86 6.12.9-arch1-1 Kernel Configuration
+#
+CONFIG_BLK_DEV_INITRD=y
+CONFIG_RD_GZIP=y
+# CONFIG_RD_LZ4 is not set
+CONFIG_RD_ZSTD=y
+CONFIG_ZSTD_DECOMPRESS=m
+CONFIG_LOCALVERSION=\"\"
+";
+
+    fn mock_config(options: HashMap<Box<str>, Box<str>>) -> KernelConfig {
+        KernelConfig {
+            release: "6.12.9-arch1-1".into(),
+            path: PathBuf::from("/boot/config-6.12.9-arch1-1"),
+            options,
+        }
+    }
+
+    #[test]
+    fn parses_options() {
+        let config = mock_config(parse_options(EXAMPLE));
+
+        assert!(config.is_enabled("CONFIG_BLK_DEV_INITRD"), "built in");
+        assert!(config.is_enabled("CONFIG_RD_ZSTD"), "built in");
+        assert!(config.is_enabled("CONFIG_ZSTD_DECOMPRESS"), "built as module");
+        assert!(!config.is_enabled("CONFIG_RD_LZ4"), "explicitly not set");
+        assert!(!config.is_enabled("CONFIG_RD_XZ"), "missing");
+        assert!(!config.is_enabled("CONFIG_LOCALVERSION"), "not a boolean");
+        assert_eq!(config.options.len(), 5);
+    }
+
+    #[test]
+    fn reads_compressed_config() {
+        let dir = TempDir::new().unwrap();
+
+        let plain = dir.path().join("config");
+        std::fs::write(&plain, EXAMPLE).unwrap();
+        assert_eq!(read_config(&plain).unwrap(), EXAMPLE);
+
+        let compressed = dir.path().join("config.gz");
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(EXAMPLE.as_bytes()).unwrap();
+        std::fs::write(&compressed, encoder.finish().unwrap()).unwrap();
+        assert_eq!(read_config(&compressed).unwrap(), EXAMPLE);
+    }
+
+    #[test]
+    fn missing_release_has_no_config() {
+        let config = KernelConfig::load("0.0.0-does-not-exist").unwrap();
+        assert_eq!(config, None);
+    }
+}
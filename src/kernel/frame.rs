@@ -0,0 +1,391 @@
+//! Frame headers of compressed images, and what the kernel decompressor needs for them.
+//!
+//! See `lib/decompress_*.c` and `lib/xz/` in the Linux sources.
+
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use anyhow::{Context, Result, bail};
+use byte_unit::{Byte, UnitType};
+
+/// Magic number for zstd frames.
+const ZSTD_MAGIC: u32 = 0xFD2F_B528;
+/// Largest zstd window accepted by the kernel, from `ZSTD_WINDOWSIZE_MAX`.
+const ZSTD_KERNEL_WINDOW_MAX: u64 = 1 << 27;
+/// Magic bytes for xz streams.
+const XZ_MAGIC: &[u8; 6] = b"\xFD7zXZ\0";
+/// Filter ID for LZMA2 in xz block headers.
+const XZ_FILTER_LZMA2: u64 = 0x21;
+/// Magic number for the LZ4 frame format.
+const LZ4_FRAME_MAGIC: u32 = 0x184D_2204;
+/// Magic number for the LZ4 legacy format, used by `lz4 -l`.
+const LZ4_LEGACY_MAGIC: u32 = 0x184C_2102;
+/// Block size of the LZ4 legacy format.
+const LZ4_LEGACY_BLOCK_SIZE: u64 = 8 << 20;
+
+/// Enough data for any of the supported headers (the xz block header is at most 1 KiB).
+const HEADER_MAX_SIZE: u64 = 12 + 1024;
+
+/// Integrity check used in xz streams.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum XzCheck {
+    /// No integrity check.
+    None,
+    /// CRC32, used by `mkinitcpio`.
+    Crc32,
+    /// CRC64, default for `xz`.
+    Crc64,
+    /// SHA-256.
+    Sha256,
+    /// Reserved check IDs.
+    Reserved(u8),
+}
+
+impl XzCheck {
+    /// Check type from xz stream flags.
+    const fn from_id(id: u8) -> Self {
+        match id {
+            0x00 => Self::None,
+            0x01 => Self::Crc32,
+            0x04 => Self::Crc64,
+            0x0A => Self::Sha256,
+            _ => Self::Reserved(id),
+        }
+    }
+}
+
+/// Relevant information from the first frame header of a compressed image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum FrameHeader {
+    /// Zstandard frame.
+    Zstd {
+        /// Window size required for decompression.
+        window_size: u64,
+        /// If the frame has a content checksum.
+        checksum: bool,
+    },
+    /// Xz stream.
+    Xz {
+        /// Integrity check for the stream.
+        check: XzCheck,
+        /// LZMA2 dictionary size from the first block, if any.
+        dictionary_size: Option<u64>,
+    },
+    /// LZ4 frame format.
+    Lz4Frame {
+        /// Maximum block size.
+        block_size: u64,
+    },
+    /// LZ4 legacy format.
+    Lz4Legacy,
+    /// Unrecognized format.
+    Unknown,
+}
+
+impl FrameHeader {
+    /// Read the header at the start of a compressed file.
+    ///
+    /// # Errors
+    ///
+    /// File cannot be read, or it has a known magic number but an invalid header.
+    pub fn read(path: &Path) -> Result<Self> {
+        let mut data = Vec::new();
+        File::open(path)?.take(HEADER_MAX_SIZE).read_to_end(&mut data)?;
+        Self::parse(&data).with_context(|| format!("invalid frame header in {}", path.display()))
+    }
+
+    /// Parse the first frame header in `data`.
+    ///
+    /// # Errors
+    ///
+    /// Known magic number, but invalid or truncated header.
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if data.starts_with(XZ_MAGIC) {
+            return parse_xz(data);
+        }
+        match data.first_chunk().copied().map(u32::from_le_bytes) {
+            Some(ZSTD_MAGIC) => parse_zstd(&data[4..]),
+            Some(LZ4_FRAME_MAGIC) => parse_lz4_frame(&data[4..]),
+            Some(LZ4_LEGACY_MAGIC) => Ok(Self::Lz4Legacy),
+            Some(_) | None => Ok(Self::Unknown),
+        }
+    }
+
+    /// Memory used by the kernel decompressor for buffers, mainly the window or dictionary.
+    #[must_use]
+    pub const fn decompression_memory(&self) -> Option<Byte> {
+        match *self {
+            Self::Zstd { window_size, .. } => Some(Byte::from_u64(window_size)),
+            Self::Xz { dictionary_size, .. } => match dictionary_size {
+                Some(size) => Some(Byte::from_u64(size)),
+                None => None,
+            },
+            Self::Lz4Frame { block_size } => Some(Byte::from_u64(block_size)),
+            Self::Lz4Legacy => Some(Byte::from_u64(LZ4_LEGACY_BLOCK_SIZE)),
+            Self::Unknown => None,
+        }
+    }
+
+    /// Reason why the kernel would reject this image at boot, if any.
+    #[must_use]
+    pub const fn boot_issue(&self) -> Option<&'static str> {
+        match *self {
+            Self::Zstd { window_size, .. } if window_size > ZSTD_KERNEL_WINDOW_MAX => {
+                Some("zstd window is larger than the kernel supports (128 MiB)")
+            }
+            Self::Xz { check, .. } if !matches!(check, XzCheck::None | XzCheck::Crc32) => {
+                Some("xz integrity check is not supported by the kernel (use --check=crc32)")
+            }
+            Self::Lz4Frame { .. } => Some("lz4 frame format is not supported by the kernel (use -l)"),
+            Self::Zstd { .. } | Self::Xz { .. } | Self::Lz4Legacy | Self::Unknown => None,
+        }
+    }
+}
+
+impl fmt::Display for FrameHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::Zstd { window_size, checksum } => {
+                let window = Byte::from_u64(window_size).get_appropriate_unit(UnitType::Binary);
+                write!(f, "zstd (window={window}, checksum={checksum})")
+            }
+            Self::Xz {
+                check,
+                dictionary_size: Some(size),
+            } => {
+                let dictionary = Byte::from_u64(size).get_appropriate_unit(UnitType::Binary);
+                write!(f, "xz (check={check:?}, dictionary={dictionary})")
+            }
+            Self::Xz {
+                check,
+                dictionary_size: None,
+            } => write!(f, "xz (check={check:?})"),
+            Self::Lz4Frame { block_size } => {
+                let block = Byte::from_u64(block_size).get_appropriate_unit(UnitType::Binary);
+                write!(f, "lz4 frame (block={block})")
+            }
+            Self::Lz4Legacy => f.write_str("lz4 legacy"),
+            Self::Unknown => f.write_str("unknown"),
+        }
+    }
+}
+
+/// Parse a zstd frame header, after the magic number.
+///
+/// See [RFC 8878](https://www.rfc-editor.org/rfc/rfc8878#name-frame-header).
+fn parse_zstd(data: &[u8]) -> Result<FrameHeader> {
+    let Some((&descriptor, data)) = data.split_first() else {
+        bail!("truncated zstd frame header");
+    };
+    let single_segment = descriptor & 0x20 != 0;
+    let checksum = descriptor & 0x04 != 0;
+
+    let window_size = if single_segment {
+        // window size is the content size, after the dictionary ID
+        let dict_id_size = [0, 1, 2, 4][usize::from(descriptor & 0x03)];
+        let content = data.get(dict_id_size..).unwrap_or_default();
+        match descriptor >> 6 {
+            0 => content.first().copied().map(u64::from),
+            1 => content
+                .first_chunk()
+                .map(|&bytes| u64::from(u16::from_le_bytes(bytes)) + 256),
+            2 => content.first_chunk().map(|&bytes| u64::from(u32::from_le_bytes(bytes))),
+            _ => content.first_chunk().map(|&bytes| u64::from_le_bytes(bytes)),
+        }
+        .context("truncated zstd frame content size")?
+    } else {
+        let Some(&window_descriptor) = data.first() else {
+            bail!("truncated zstd window descriptor");
+        };
+        let window_log = 10 + u32::from(window_descriptor >> 3);
+        let window_base = 1_u64 << window_log;
+        window_base + (window_base >> 3) * u64::from(window_descriptor & 0x07)
+    };
+
+    Ok(FrameHeader::Zstd { window_size, checksum })
+}
+
+/// Parse a xz stream header and the first block header.
+///
+/// See the [xz file format](https://tukaani.org/xz/xz-file-format.txt).
+fn parse_xz(data: &[u8]) -> Result<FrameHeader> {
+    let Some(&[_, flags]) = data.get(6..8) else {
+        bail!("truncated xz stream header");
+    };
+    let check = XzCheck::from_id(flags & 0x0F);
+
+    // an index indicator (zero) means there are no blocks
+    let dictionary_size = match data.get(12..) {
+        Some([0, ..]) => None,
+        Some(block) => xz_lzma2_dictionary(block)?,
+        None => bail!("truncated xz stream header"),
+    };
+    Ok(FrameHeader::Xz { check, dictionary_size })
+}
+
+/// Find the LZMA2 dictionary size in a xz block header.
+fn xz_lzma2_dictionary(block: &[u8]) -> Result<Option<u64>> {
+    let Some(&[size, flags]) = block.get(..2) else {
+        bail!("truncated xz block header");
+    };
+    let Some(mut data) = block.get(2..(usize::from(size) + 1) * 4) else {
+        bail!("truncated xz block header");
+    };
+
+    if flags & 0x40 != 0 {
+        xz_varint(&mut data)?; // compressed size
+    }
+    if flags & 0x80 != 0 {
+        xz_varint(&mut data)?; // uncompressed size
+    }
+
+    for _ in 0..=(flags & 0x03) {
+        let filter = xz_varint(&mut data)?;
+        let props_size = usize::try_from(xz_varint(&mut data)?)?;
+        let Some(props) = data.get(..props_size) else {
+            bail!("truncated xz filter properties");
+        };
+        if filter == XZ_FILTER_LZMA2 {
+            let Some(&bits) = props.first() else {
+                bail!("missing LZMA2 dictionary size");
+            };
+            return match bits {
+                0..40 => Ok(Some(u64::from(2 | (bits & 1)) << ((bits >> 1) + 11))),
+                40 => Ok(Some(u64::from(u32::MAX))),
+                _ => bail!("invalid LZMA2 dictionary size: {bits}"),
+            };
+        }
+        data = &data[props_size..];
+    }
+    Ok(None)
+}
+
+/// Decode a xz variable-length integer, advancing `data` past it.
+fn xz_varint(data: &mut &[u8]) -> Result<u64> {
+    let mut value = 0_u64;
+    for (idx, &byte) in data.iter().take(9).enumerate() {
+        value |= u64::from(byte & 0x7F) << (7 * idx);
+        if byte & 0x80 == 0 {
+            *data = &data[idx + 1..];
+            return Ok(value);
+        }
+    }
+    bail!("invalid xz variable-length integer")
+}
+
+/// Parse a LZ4 frame descriptor, after the magic number.
+///
+/// See the [LZ4 frame format](https://github.com/lz4/lz4/blob/dev/doc/lz4_Frame_format.md).
+fn parse_lz4_frame(data: &[u8]) -> Result<FrameHeader> {
+    let Some(&[_, block_descriptor]) = data.get(..2) else {
+        bail!("truncated lz4 frame descriptor");
+    };
+    let block_size = match (block_descriptor >> 4) & 0x07 {
+        4 => 64 << 10,
+        5 => 256 << 10,
+        6 => 1 << 20,
+        7 => 4 << 20,
+        other => bail!("invalid lz4 block size: {other}"),
+    };
+    Ok(FrameHeader::Lz4Frame { block_size })
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use test_log::test;
+
+    use super::*;
+
+    #[test]
+    fn parses_zstd_window() {
+        // zstd -19 --long: window_log=27
+        let header = FrameHeader::parse(b"\x28\xB5\x2F\xFD\x04\x88").unwrap();
+        assert_eq!(
+            header,
+            FrameHeader::Zstd {
+                window_size: 128 << 20,
+                checksum: true
+            }
+        );
+        assert_eq!(header.boot_issue(), None);
+        assert_eq!(header.to_string(), "zstd (window=128 MiB, checksum=true)");
+
+        // window_log=28, mantissa=1
+        let header = FrameHeader::parse(b"\x28\xB5\x2F\xFD\x00\x91").unwrap();
+        assert_eq!(header.decompression_memory(), Some(Byte::from_u64((256 << 20) + (32 << 20))));
+        assert_eq!(header.boot_issue(), Some("zstd window is larger than the kernel supports (128 MiB)"));
+
+        // single segment, 2 byte content size
+        let header = FrameHeader::parse(b"\x28\xB5\x2F\xFD\x60\x00\x01").unwrap();
+        assert_eq!(
+            header,
+            FrameHeader::Zstd {
+                window_size: 256 + 256,
+                checksum: false
+            }
+        );
+
+        let error = FrameHeader::parse(b"\x28\xB5\x2F\xFD\x04").unwrap_err();
+        assert_eq!(error.to_string(), "truncated zstd window descriptor");
+    }
+
+    #[test]
+    fn parses_xz_check_and_dictionary() {
+        // xz --check=crc32 -6: LZMA2 with 8 MiB dictionary
+        let header =
+            FrameHeader::parse(b"\xFD7zXZ\0\x00\x01\x69\x22\xDE\x36\x02\x00\x21\x01\x16\0\0\0\0\0\0\0").unwrap();
+        assert_eq!(
+            header,
+            FrameHeader::Xz {
+                check: XzCheck::Crc32,
+                dictionary_size: Some(8 << 20)
+            }
+        );
+        assert_eq!(header.boot_issue(), None);
+        assert_eq!(header.to_string(), "xz (check=Crc32, dictionary=8 MiB)");
+
+        // default xz, CRC64 and sizes in block header
+        let header =
+            FrameHeader::parse(b"\xFD7zXZ\0\x00\x04\xE6\xD6\xB4\x46\x03\xC0\x80\x01\x90\x03\x21\x01\x18\0\0\0\0\0\0\0")
+                .unwrap();
+        assert_eq!(
+            header,
+            FrameHeader::Xz {
+                check: XzCheck::Crc64,
+                dictionary_size: Some(16 << 20)
+            }
+        );
+        assert_eq!(header.boot_issue(), Some("xz integrity check is not supported by the kernel (use --check=crc32)"));
+
+        // empty stream
+        let header = FrameHeader::parse(b"\xFD7zXZ\0\x00\x01\x69\x22\xDE\x36\x00\x00\x00\x00").unwrap();
+        assert_eq!(header.decompression_memory(), None);
+    }
+
+    #[test]
+    fn parses_lz4_formats() {
+        let header = FrameHeader::parse(b"\x04\x22\x4D\x18\x64\x70\xB9").unwrap();
+        assert_eq!(header, FrameHeader::Lz4Frame { block_size: 4 << 20 });
+        assert_eq!(header.boot_issue(), Some("lz4 frame format is not supported by the kernel (use -l)"));
+
+        let header = FrameHeader::parse(b"\x02\x21\x4C\x18\x00\x00").unwrap();
+        assert_eq!(header, FrameHeader::Lz4Legacy);
+        assert_eq!(header.decompression_memory(), Some(Byte::from_u64(8 << 20)));
+        assert_eq!(header.boot_issue(), None);
+    }
+
+    #[test]
+    fn unknown_formats_have_no_issues() {
+        for data in [b"".as_slice(), b"\x1F\x8B\x08\x00", b"070701"] {
+            let header = FrameHeader::parse(data).unwrap();
+            assert_eq!(header, FrameHeader::Unknown);
+            assert_eq!(header.decompression_memory(), None);
+            assert_eq!(header.boot_issue(), None);
+        }
+    }
+}
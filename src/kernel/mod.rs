@@ -0,0 +1,9 @@
+//! Capabilities of the kernel targeted by a preset.
+
+mod config;
+mod frame;
+mod release;
+
+pub use config::KernelConfig;
+pub use frame::FrameHeader;
+pub use release::release;
@@ -0,0 +1,120 @@
+//! Resolve the kernel release from a preset `kver`.
+
+use std::path::Path;
+
+use anyhow::{Context, Result, bail};
+
+use crate::bash::BashString;
+
+/// Position of the `HdrS` magic in x86 boot images.
+const HEADER_MAGIC_OFFSET: usize = 0x202;
+/// Position of the kernel version pointer in x86 boot images.
+const VERSION_POINTER_OFFSET: usize = 0x20E;
+/// Base address for the kernel version pointer.
+const VERSION_BASE: usize = 0x200;
+/// Maximum length of the kernel version string.
+const VERSION_MAX_LEN: usize = 127;
+
+/// Kernel release (as in `uname -r`) for a preset `kver`.
+///
+/// Same rules as `mkinitcpio`: an absolute path is read as a kernel image, other values are used as is and a missing
+/// `kver` means the running kernel.
+///
+/// # Errors
+///
+/// Kernel image cannot be read or is not an x86 boot image.
+pub fn release(kver: Option<&BashString>) -> Result<Box<str>> {
+    let Some(kver) = kver else {
+        return running_release();
+    };
+
+    if kver.as_raw().starts_with(b"/") {
+        let path = kver.as_path();
+        let image = std::fs::read(path).with_context(|| format!("could not read kernel image {}", path.display()))?;
+        image_release(&image).with_context(|| format!("invalid kernel image {}", path.display()))
+    } else {
+        Ok(kver.to_utf8_lossy().trim().into())
+    }
+}
+
+/// Release of the currently running kernel.
+///
+/// # Errors
+///
+/// Could not read `/proc/sys/kernel/osrelease`.
+pub fn running_release() -> Result<Box<str>> {
+    let release = std::fs::read_to_string(Path::new("/proc/sys/kernel/osrelease"))?;
+    Ok(release.trim().into())
+}
+
+/// Scrape the version string from an x86 boot image.
+///
+/// See `kver_x86` in `mkinitcpio`'s `functions`.
+fn image_release(image: &[u8]) -> Result<Box<str>> {
+    if image.get(HEADER_MAGIC_OFFSET..HEADER_MAGIC_OFFSET + 4) != Some(b"HdrS") {
+        bail!("missing x86 boot header");
+    }
+    let Some(&[low, high]) = image.get(VERSION_POINTER_OFFSET..VERSION_POINTER_OFFSET + 2) else {
+        bail!("missing kernel version pointer");
+    };
+
+    let offset = VERSION_BASE + usize::from(u16::from_le_bytes([low, high]));
+    let Some(version) = image.get(offset..) else {
+        bail!("kernel version pointer out of bounds: {offset:#x}");
+    };
+    let version = &version[..version.len().min(VERSION_MAX_LEN)];
+
+    let release = version
+        .split(|byte| byte.is_ascii_whitespace() || *byte == b'\0')
+        .next()
+        .filter(|release| !release.is_empty())
+        .context("empty kernel version")?;
+    Ok(String::from_utf8_lossy(release).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use test_log::test;
+
+    use super::*;
+
+    fn mock_image(version: &[u8]) -> Vec<u8> {
+        let mut image = vec![0_u8; 0x400];
+        image[HEADER_MAGIC_OFFSET..HEADER_MAGIC_OFFSET + 4].copy_from_slice(b"HdrS");
+        image[VERSION_POINTER_OFFSET..VERSION_POINTER_OFFSET + 2].copy_from_slice(&0x200_u16.to_le_bytes());
+        image.extend_from_slice(version);
+        image
+    }
+
+    #[test]
+    fn reads_x86_image_version() {
+        let image = mock_image(b"6.12.9-arch1-1 (linux@archlinux) #1 SMP PREEMPT_DYNAMIC\0");
+        assert_eq!(image_release(&image).unwrap().as_ref(), "6.12.9-arch1-1");
+
+        let image = mock_image(b"6.6.70-1-lts\0");
+        assert_eq!(image_release(&image).unwrap().as_ref(), "6.6.70-1-lts");
+    }
+
+    #[test]
+    fn rejects_invalid_images() {
+        let error = image_release(b"not a kernel").unwrap_err();
+        assert_eq!(error.to_string(), "missing x86 boot header");
+
+        let mut image = mock_image(b"");
+        image.truncate(0x300);
+        let error = image_release(&image).unwrap_err();
+        assert_eq!(error.to_string(), "kernel version pointer out of bounds: 0x400");
+
+        let image = mock_image(b" 6.12.9");
+        let error = image_release(&image).unwrap_err();
+        assert_eq!(error.to_string(), "empty kernel version");
+    }
+
+    #[test]
+    fn running_kernel_has_release() {
+        let release = running_release().unwrap();
+        assert!(!release.is_empty(), "release should not be empty");
+        assert!(!release.contains(char::is_whitespace), "release is a single word");
+    }
+}
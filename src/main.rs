@@ -55,103 +55,296 @@
 #![warn(clippy::wildcard_enum_match_arm)]
 #![warn(clippy::unnecessary_self_imports)]
 
+use std::collections::BTreeMap;
+use std::num::{NonZeroU32, NonZeroUsize};
 use std::os::unix::ffi::OsStringExt;
 use std::panic;
 use std::path::{Path, PathBuf};
 use std::process::ExitCode;
-use std::time::Instant;
+use std::time::{Duration, Instant, SystemTime};
 
-use anyhow::Result;
-use byte_unit::UnitType;
-use clap::Parser;
+use anyhow::{Context, Result, anyhow, bail};
+use byte_unit::Byte;
+use clap::{Parser, Subcommand};
 
+mod artifacts;
 mod bash;
+mod cache;
+mod compression;
+mod environment;
+mod history;
+mod kernel;
+mod manifest;
 mod measure;
 mod mkinitcpio;
+mod plan;
+mod report;
+mod rundir;
+mod stats;
 mod sudo;
+mod tools;
 mod user_spec;
 mod utils;
+mod workdir;
 
-use crate::measure::{Stats, exec};
+use crate::artifacts::{Artifacts, JobSpace, KeepArtifacts};
+use crate::cache::CacheMode;
+use crate::compression::{COMPRESSION, Threads};
+use crate::environment::Environment;
+use crate::history::Record;
+use crate::kernel::{FrameHeader, KernelConfig};
+use crate::manifest::{Manifest, System};
+use crate::measure::{CpuList, IoPriority, Limits, Policy, Schedule, Stats};
 use crate::mkinitcpio::{Config, Preset, create_mock_preset, mkinitcpio};
+use crate::plan::{Job, Plan, Target};
+use crate::report::{
+    Benchmark, BootDecompress, Failure, Outcome, Phase, export_timeline, log_boot_summary, log_digests, log_header,
+    log_scaling, log_significance, log_size, log_stats, log_summary,
+};
+use crate::rundir::{LogTee, RunDir};
+use crate::stats::{OutlierMethod, Outliers, Test};
+use crate::tools::{Tool, ToolPath, Tools};
 use crate::user_spec::UserSpec;
-
-/// A compression method to be tested.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
-struct Compression {
-    /// Unique method name.
-    name: &'static str,
-    /// Extension for compressed file.
-    extension: &'static str,
-    /// Compress a file.
-    compress: fn(path: &Path) -> Result<Stats>,
-    /// Decompress a file.
-    decompress: fn(path: &Path) -> Result<Stats>,
-}
-
-/// List of compression methods to test.
-const COMPRESSION: &[Compression] = &[
-    Compression {
-        name: "lz4-fast",
-        extension: ".lz4",
-        compress: |path| exec("/usr/bin/lz4", ["-v".as_ref(), "-12".as_ref(), path.as_os_str()]),
-        decompress: |path| exec("/usr/bin/lz4", ["-v".as_ref(), "-d".as_ref(), path.as_os_str()]),
-    },
-    Compression {
-        name: "lz4-norm",
-        extension: ".lz4",
-        compress: |path| exec("/usr/bin/lz4", ["-v".as_ref(), path.as_os_str()]),
-        decompress: |path| exec("/usr/bin/lz4", ["-v".as_ref(), "-d".as_ref(), path.as_os_str()]),
-    },
-    Compression {
-        name: "lz4-high",
-        extension: ".lz4",
-        compress: |path| exec("/usr/bin/lz4", ["-v".as_ref(), "--fast=12".as_ref(), path.as_os_str()]),
-        decompress: |path| exec("/usr/bin/lz4", ["-v".as_ref(), "-d".as_ref(), path.as_os_str()]),
-    },
-    Compression {
-        name: "zstd-fast",
-        extension: ".zst",
-        compress: |path| exec("/usr/bin/zstdmt", ["-v".as_ref(), "-1".as_ref(), path.as_os_str()]),
-        decompress: |path| exec("/usr/bin/zstdmt", ["-v".as_ref(), "-d".as_ref(), path.as_os_str()]),
-    },
-    Compression {
-        name: "zstd-norm",
-        extension: ".zst",
-        compress: |path| exec("/usr/bin/zstdmt", ["-v".as_ref(), "-5".as_ref(), "--long".as_ref(), path.as_os_str()]),
-        decompress: |path| exec("/usr/bin/zstdmt", ["-v".as_ref(), "-d".as_ref(), path.as_os_str()]),
-    },
-    Compression {
-        name: "zstd-high",
-        extension: ".zst",
-        compress: |path| exec("/usr/bin/zstdmt", ["-v".as_ref(), "-19".as_ref(), "--long".as_ref(), path.as_os_str()]),
-        decompress: |path| exec("/usr/bin/zstdmt", ["-v".as_ref(), "-d".as_ref(), path.as_os_str()]),
-    },
-];
+use crate::utils::digest::Digest;
+use crate::workdir::{WorkFiles, Workdir, WorkdirSpec};
 
 /// Run some benchmarks on mkinitcpio compression and decompression algorithms
 #[derive(Parser, Debug, Clone)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, args_override_self = true)]
 struct Cli {
+    /// Compare previous runs instead of running the benchmarks.
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Directory to place output files.
-    #[arg(short, long, default_value = "./output", required = false)]
+    #[arg(short, long, default_value = "./output", required = false, global = true)]
     outdir: PathBuf,
 
     /// Set owner for output directories and files.
     #[arg(short, long, value_name = "[OWNER][:[GROUP]]", default_value = ":", required = false)]
     chown: UserSpec,
+
+    /// Memory the kernel may use to decompress the initramfs at boot.
+    ///
+    /// Methods whose window or dictionary is larger than this are flagged.
+    #[arg(long, value_name = "SIZE", default_value = "64 MiB", required = false)]
+    memory_limit: Byte,
+
+    /// Use a specific binary for a compression tool, instead of searching `PATH`.
+    #[arg(long, value_name = "NAME=PATH", required = false)]
+    tool: Vec<ToolPath>,
+
+    /// Run each command in a transient cgroup v2, measuring all processes it spawns.
+    ///
+    /// Adds peak memory, CPU and I/O usage of the whole process tree to the results.
+    #[arg(long, required = false)]
+    cgroup: bool,
+
+    /// Time limit for each mkinitcpio run, like `5m` or `90s`.
+    ///
+    /// Commands running longer are killed along with their process group. Unlimited by default.
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration, required = false)]
+    mkinitcpio_timeout: Option<Duration>,
+
+    /// Time limit for each compression, like `5m` or `90s`.
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration, required = false)]
+    compress_timeout: Option<Duration>,
+
+    /// Time limit for each decompression, like `5m` or `90s`.
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration, required = false)]
+    decompress_timeout: Option<Duration>,
+
+    /// Output kept in memory for each stream of a command.
+    ///
+    /// Lines are logged as they arrive, but only the end of the output is kept for error reports.
+    #[arg(long, value_name = "SIZE", default_value = "1 MiB", required = false)]
+    output_limit: Byte,
+
+    /// Sample memory, CPU and I/O of each command and the processes it starts at this interval, like `50ms`.
+    ///
+    /// Samples are written as CSV files under `OUTDIR/timeline`.
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration, required = false)]
+    sample_interval: Option<Duration>,
+
+    /// Page cache state before each compression and decompression.
+    ///
+    /// Cold modes need work files on disk, not in a tmpfs or memfds.
+    #[arg(long, value_enum, default_value_t, required = false)]
+    cache: CacheMode,
+
+    /// Directory for the files of each compression and decompression, `tmpfs` for a tmpfs mount in a private
+    /// mount namespace, or `memfd` for anonymous memory files.
+    ///
+    /// A temporary directory is created inside, and only the compressed images are kept in the output directory.
+    /// By default, all files are kept in the output directory. With `memfd`, tools read from stdin and write to
+    /// stdout, so they don't know the size of their input.
+    #[arg(long, value_name = "DIR|tmpfs|memfd", required = false)]
+    workdir: Option<WorkdirSpec>,
+
+    /// Images kept in the output directory after each benchmark.
+    ///
+    /// Free space in the output directory is checked before the first benchmark, from the size of the raw images
+    /// and the images kept.
+    #[arg(long, value_enum, default_value_t, required = false)]
+    keep_artifacts: KeepArtifacts,
+
+    /// Pin measured commands to these CPUs, like `2-3` or `0,4`.
+    #[arg(long, value_name = "LIST", required = false)]
+    cpus: Option<CpuList>,
+
+    /// Nice level for measured commands, from -20 to 19.
+    #[arg(long, value_name = "N", allow_negative_numbers = true, required = false)]
+    nice: Option<i32>,
+
+    /// Scheduling policy for measured commands.
+    #[arg(long, value_enum, required = false)]
+    sched: Option<Policy>,
+
+    /// I/O priority for measured commands, as `realtime`, `best-effort` or `idle`, with an optional level.
+    #[arg(long, value_name = "CLASS[:LEVEL]", required = false)]
+    ioprio: Option<IoPriority>,
+
+    /// Compress with each of these thread counts, like `1,2,4,all`, for multithreaded tools.
+    ///
+    /// Results show how time, CPU time, peak memory and compressed size scale with the threads. By default,
+    /// multithreaded tools are given one thread per usable CPU, from `--cpus` or the CPU affinity.
+    #[arg(long, value_name = "N|all", value_delimiter = ',', required = false)]
+    threads: Vec<Threads>,
+
+    /// Run each benchmark this many times.
+    #[arg(long, value_name = "N", default_value = "1", required = false)]
+    repeat: NonZeroU32,
+
+    /// Seed for the random order of the benchmarks, to reproduce a previous run.
+    ///
+    /// All benchmarks are interleaved in a random order, so throttling and cache state don't favor the same methods.
+    /// The seed is shown in the results. Random by default.
+    #[arg(long, value_name = "N", required = false)]
+    seed: Option<u64>,
+
+    /// Test deciding whether two methods really differ, with a `--repeat` of at least 2, or 4 for `mann-whitney`.
+    ///
+    /// Each pair of methods is compared for each preset and target, with 95% confidence intervals. Differences that
+    /// may come from noise are marked as not significant, here and in `compare`. With fewer repetitions, Mann-Whitney
+    /// can't find any difference, so the tests are skipped.
+    #[arg(long, value_enum, default_value_t, required = false, global = true)]
+    significance: Test,
+
+    /// Rule flagging outliers among the repetitions of a method, with a `--repeat` of at least 3.
+    ///
+    /// The number of outliers is reported for each metric, with their raw values. All repetitions are kept in the
+    /// history.
+    #[arg(long, value_enum, default_value_t, required = false, global = true)]
+    outliers: OutlierMethod,
+
+    /// Leave outliers out of the significance tests, here and in `compare`, and out of the median times in `compare`.
+    #[arg(long, required = false, global = true)]
+    exclude_outliers: bool,
+
+    /// Refuse to measure on a noisy machine, instead of only warning.
+    ///
+    /// Load average, pressure stall information, CPU frequency governor, temperatures and battery are checked before
+    /// the run. Before each benchmark, running tasks and pressure are compared to that first reading, waiting up to
+    /// 30s for the machine to settle after the previous benchmark.
+    #[arg(long, required = false)]
+    strict: bool,
+
+    /// Decompress again with the CPU bandwidth of this many CPUs, emulating early boot.
+    ///
+    /// Any `--boot-*` option adds a decompression under those limits, run in a transient cgroup v2. Methods that
+    /// slow down or fail under the limits are reported at the end.
+    #[arg(long, value_name = "N", required = false)]
+    boot_cpus: Option<NonZeroU32>,
+
+    /// Decompress again restricted to these CPUs, like `0`, emulating early boot.
+    #[arg(long, value_name = "LIST", required = false)]
+    boot_cpuset: Option<CpuList>,
+
+    /// Decompress again with this memory limit, like `256 MiB`, emulating early boot.
+    #[arg(long, value_name = "SIZE", required = false)]
+    boot_memory: Option<Byte>,
+
+    /// Keep only the last N runs in the output directory, removing older ones.
+    ///
+    /// Each run is written to its own timestamped directory, with a `latest` symlink to the most recent one.
+    #[arg(long, value_name = "N", required = false)]
+    keep: Option<NonZeroUsize>,
+}
+
+/// Operations on previous results.
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+    /// Compare the results of two runs from the history in the output directory.
+    ///
+    /// Shows the change of size, ratio, compression and decompression times for each preset, method and target.
+    Compare {
+        /// Older run, by directory name like `20250101T120000Z`, second to last run by default.
+        old: Option<String>,
+        /// Newer run, by directory name, last run by default.
+        new: Option<String>,
+    },
+}
+
+impl Cli {
+    /// How outliers in repeated measurements are handled.
+    const fn outliers(&self) -> Outliers {
+        Outliers {
+            method: self.outliers,
+            exclude: self.exclude_outliers,
+        }
+    }
+
+    /// How commands are measured, with a time limit for the phase.
+    fn measure_options(&self, timeout: Option<Duration>) -> measure::Options {
+        measure::Options {
+            cgroup: self.cgroup,
+            timeout,
+            output_limit: self.output_limit,
+            sample_interval: self.sample_interval,
+            schedule: self.schedule(),
+            limits: Limits::default(),
+        }
+    }
+
+    /// Resource limits for decompression in early boot.
+    fn boot_limits(&self) -> Limits {
+        Limits {
+            cpus: self.boot_cpus,
+            cpuset: self.boot_cpuset.clone(),
+            memory: self.boot_memory,
+        }
+    }
+
+    /// CPU affinity, scheduling and I/O priority for measured commands.
+    fn schedule(&self) -> Schedule {
+        Schedule {
+            cpus: self.cpus.clone(),
+            nice: self.nice,
+            policy: self.sched,
+            ioprio: self.ioprio,
+        }
+    }
 }
 
 /// Binary entrypoint.
 #[must_use]
 pub fn main() -> ExitCode {
-    env_logger::init();
+    let log = LogTee::default();
+    let mut builder = env_logger::Builder::from_default_env();
+    if std::env::var_os("RUST_LOG_STYLE").is_none() {
+        builder.write_style(LogTee::write_style());
+    }
+    builder.target(env_logger::Target::Pipe(Box::new(log.clone()))).init();
     let cli = Cli::parse();
-    let result = panic::catch_unwind(|| run(cli.clone()));
+    let result = panic::catch_unwind(|| run(&cli, &log));
+    measure::release_cgroups();
 
-    log::debug!("recursive_chown: owner={}, path={}", cli.chown, cli.outdir.display());
-    if let Err(error) = cli.chown.recursive_chown(&cli.outdir) {
-        log::warn!("{error}");
+    // comparing only reads the history
+    if !matches!(cli.command, Some(Command::Compare { .. })) {
+        log::debug!("recursive_chown: owner={}, path={}", cli.chown, cli.outdir.display());
+        if let Err(error) = cli.chown.recursive_chown(&cli.outdir) {
+            log::warn!("{error}");
+        }
     }
 
     result
@@ -167,9 +360,13 @@ pub fn main() -> ExitCode {
 /// # Errors
 ///
 /// Any runtime error in the program.
-fn run(cli: Cli) -> Result<ExitCode> {
-    let user = cli.chown;
-    let outdir = std::path::absolute(cli.outdir)?;
+fn run(cli: &Cli, log: &LogTee) -> Result<ExitCode> {
+    if let Some(Command::Compare { old, new }) = &cli.command {
+        return compare(cli, old.as_deref(), new.as_deref());
+    }
+
+    let user = &cli.chown;
+    let outdir = std::path::absolute(&cli.outdir)?;
     let current_user = UserSpec::current_user()?;
 
     log::debug!("current user = {}", current_user.to_spec());
@@ -180,82 +377,607 @@ fn run(cli: Cli) -> Result<ExitCode> {
         log::info!("program requires root to access mkinitcpio");
 
         let target_user = UserSpec {
-            owner: user.owner.or(current_user.owner),
-            group: user.group.or(current_user.group),
+            owner: user.owner.clone().or(current_user.owner),
+            group: user.group.clone().or(current_user.group),
         };
 
         let program = std::env::current_exe()?;
-        sudo::run0([
-            program.into_os_string().into_vec(),
-            format!("--chown={:+}", target_user.to_numeric_spec()).into(),
-            ["--outdir=".into(), outdir.into_os_string().into_vec()].concat(),
-        ])?;
+        let mut args = vec![program.into_os_string().into_vec()];
+        args.extend(std::env::args_os().skip(1).map(OsStringExt::into_vec));
+        args.push(format!("--chown={:+}", target_user.to_numeric_spec()).into());
+        if let Some(workdir) = &cli.workdir {
+            args.push(format!("--workdir={}", workdir.absolute()?).into());
+        }
+        args.push(["--outdir=".into(), outdir.into_os_string().into_vec()].concat());
+        sudo::run0(args)?;
         unreachable!("exec run0 should either replace the process or fail, ending current execution here");
     }
 
-    let mut exit_code = ExitCode::SUCCESS;
+    // before building the presets, which load the machine
+    let baseline = Environment::read();
+    log::info!("environment: {baseline}");
+    check_environment("environment", cli, &baseline.issues(compression::available_cpus()))?;
+    // otherwise every method would fail under the limits
+    cli.boot_limits().check().context("boot limits can't be enforced")?;
+
+    let started = SystemTime::now();
+    let run_dir = create_run_dir(cli, &outdir, started, log)?;
+    let outdir = run_dir.path();
+
+    let tools = Tools::discover(compression::required_tools(), &cli.tool);
+
+    let workdir = Workdir::new(cli.workdir.as_ref(), outdir)?;
+    // neither fadvise nor drop_caches evicts shared memory, so the results would be warm
+    if cli.cache != CacheMode::Warm && workdir.is_in_memory()? {
+        bail!("--cache={} can't evict work files in memory, use --cache=warm or a --workdir on disk", cli.cache);
+    }
+    let timeline_dir = outdir.join("timeline");
+
+    let mut outcomes = Vec::new();
+    let mut presets = Vec::new();
     let mut default_config = None;
-    for preset in Preset::load_default_presets()? {
-        if let Err(error) = preset_stats(preset, &outdir, &mut default_config) {
-            log::error!("preset_stats: {error}");
-            exit_code = ExitCode::FAILURE;
+    let default_presets = Preset::load_default_presets()?;
+    let config_files = mkinitcpio::config_files(&default_presets);
+    for preset in default_presets {
+        let name = preset.name.to_utf8_lossy().into_owned();
+        match build_preset(preset, cli, outdir, &timeline_dir, &mut default_config) {
+            Ok(images) => presets.push(images),
+            Err(error) => {
+                log::error!("build_preset: {error}");
+                outcomes.push(Outcome::failed(name, Failure::new(Phase::Setup, &error), None));
+            }
+        }
+    }
+
+    let jobs = plan_jobs(cli, &tools, &presets, &mut outcomes);
+    let plan = Plan::shuffled(jobs, cli.seed.unwrap_or_else(|| fastrand::u64(..)));
+    log::info!("plan: {} jobs in random order, seed={}", plan.jobs.len(), plan.seed);
+
+    check_disk_space(cli, outdir, &presets, &plan)?;
+
+    write_manifest(outdir, started, plan.seed, &baseline, &tools, &presets, config_files);
+
+    let mut results = Vec::with_capacity(plan.jobs.len());
+    let mut artifacts = Artifacts::new(cli.keep_artifacts);
+    for (position, job) in plan.jobs.iter().enumerate() {
+        log::debug!("plan: position={position}, job={job:?}");
+        let images = &presets[job.preset];
+        let outcome = run_job(job, cli, &tools, images, &workdir, &timeline_dir, &baseline);
+        let (decompressed, compressed) = images.outputs(job);
+        let size = if let Outcome::Measured(benchmark) = &outcome {
+            Some(benchmark.compressed_size)
+        } else {
+            None
+        };
+        artifacts.retain((job.preset, job.target), &decompressed, &compressed, size);
+        results.push((*job, outcome));
+    }
+    artifacts.finish(
+        presets
+            .iter()
+            .flat_map(|images| [images.image.as_path(), images.uki.as_path()]),
+    );
+
+    results.sort_unstable_by_key(|(job, _)| *job);
+    append_history(&run_dir, started, plan.seed, &presets, &results);
+    log_methods_significance(cli, &presets, &results);
+    for scenario in results.chunk_by(|(first, _), (second, _)| first.same_scenario(second)) {
+        if let [(job, _), _, ..] = scenario {
+            let name = Job { threads: None, ..*job }.name(&presets[job.preset].name);
+            log_scaling(&name, scenario.iter().map(|(_, outcome)| outcome));
         }
     }
-    Ok(exit_code)
+    outcomes.extend(results.into_iter().map(|(_, outcome)| outcome));
+
+    if !cli.boot_limits().is_unlimited() {
+        log_boot_summary(&outcomes);
+    }
+    log_summary(&outcomes);
+    log::info!("plan: seed={}", plan.seed);
+    if outcomes.iter().all(Outcome::is_ok) {
+        Ok(ExitCode::SUCCESS)
+    } else {
+        Ok(ExitCode::FAILURE)
+    }
+}
+
+/// Raw images built for a preset.
+#[derive(Debug)]
+struct PresetImages {
+    /// Preset name.
+    name: String,
+    /// Release of the targeted kernel, if resolved.
+    release: Option<Box<str>>,
+    /// Build configuration of the kernel, if found.
+    kernel_config: Option<KernelConfig>,
+    /// Initramfs image.
+    image: PathBuf,
+    /// Unified kernel image.
+    uki: PathBuf,
+}
+
+impl PresetImages {
+    /// Path of the raw image for a target.
+    fn path(&self, target: Target) -> &Path {
+        match target {
+            Target::Img => &self.image,
+            Target::Uki => &self.uki,
+        }
+    }
+
+    /// Paths of the decompressed copy and the compressed image of a job, in the output directory.
+    fn outputs(&self, job: &Job) -> (PathBuf, PathBuf) {
+        let decompressed = with_extension(self.path(job.target), &job.suffix());
+        let compressed = with_extension(&decompressed, COMPRESSION[job.compression].extension);
+        (decompressed, compressed)
+    }
 }
 
-/// Measure and display preset statistics.
-fn preset_stats(preset: Preset, output_dir: &Path, default_config: &mut Option<Config>) -> Result<()> {
+/// Build the raw images of a preset with mkinitcpio, displaying its statistics.
+fn build_preset(
+    preset: Preset,
+    cli: &Cli,
+    output_dir: &Path,
+    timeline_dir: &Path,
+    default_config: &mut Option<Config>,
+) -> Result<PresetImages> {
     let name = preset.name.to_utf8_lossy().into_owned();
+    let release = kernel::release(preset.kver.as_ref())
+        .inspect_err(|error| log::warn!("{name}: could not resolve kernel release: {error:#}"))
+        .ok();
+    let kernel_config = release
+        .as_deref()
+        .and_then(|release| load_kernel_config(&name, release));
 
     let start_time = Instant::now();
     let (preset, image, uki) = create_mock_preset(preset, output_dir, default_config)?;
     log::debug!("create_mock_preset: elapsed={:?}, preset={preset:?}", start_time.elapsed());
 
-    let stats = mkinitcpio(&preset)?;
-    log_stats(&name, &stats);
+    let stats = mkinitcpio(&preset, &cli.measure_options(cli.mkinitcpio_timeout))?;
+    log_stats(&name, &stats, None);
+    export(timeline_dir, &name, Phase::Setup, &stats);
+
+    Ok(PresetImages {
+        name,
+        release,
+        kernel_config,
+        image,
+        uki,
+    })
+}
+
+/// List the jobs for all presets, methods, targets, thread counts and repetitions.
+///
+/// Methods that can't be tested for a preset are added to `outcomes` as skipped.
+fn plan_jobs(cli: &Cli, tools: &Tools, presets: &[PresetImages], outcomes: &mut Vec<Outcome>) -> Vec<Job> {
+    let repetitions: Vec<_> = if cli.repeat == NonZeroU32::MIN {
+        vec![None]
+    } else {
+        (1..=cli.repeat.get()).map(Some).collect()
+    };
+
+    let mut jobs = Vec::new();
+    for (preset, images) in presets.iter().enumerate() {
+        let name = &images.name;
+        for (idx, compression) in COMPRESSION.iter().enumerate() {
+            log::debug!("plan_jobs: idx={idx}, compression={compression:?}");
+            let mut skip = |reason: String| {
+                log::warn!("{name}/{}: skipped, {reason}", compression.name);
+                outcomes.push(Outcome::Skipped {
+                    name: format!("{name}/{}", compression.name),
+                    reason,
+                });
+            };
+
+            if let Some(config) = &images.kernel_config
+                && !config.is_enabled(compression.kernel_option)
+            {
+                skip(format!("kernel {} was built without {}", config.release(), compression.kernel_option));
+                continue;
+            }
+            if let Err(reason) = tools.get(compression.tool) {
+                skip(format!("{}: {reason}", compression.tool));
+                continue;
+            }
+
+            let sweep = if compression.is_multithreaded() && !cli.threads.is_empty() {
+                cli.threads.iter().copied().map(Some).collect()
+            } else {
+                vec![None]
+            };
+            for target in [Target::Img, Target::Uki] {
+                for &repetition in &repetitions {
+                    for (position, &threads) in sweep.iter().enumerate() {
+                        jobs.push(Job {
+                            preset,
+                            compression: idx,
+                            target,
+                            repetition,
+                            sweep: position,
+                            threads,
+                        });
+                    }
+                }
+            }
+        }
+    }
+    jobs
+}
+
+/// Run a job and display its results.
+fn run_job(
+    job: &Job,
+    cli: &Cli,
+    tools: &Tools,
+    images: &PresetImages,
+    workdir: &Workdir,
+    timeline_dir: &Path,
+    baseline: &Environment,
+) -> Outcome {
+    let compression = &COMPRESSION[job.compression];
+    let name = job.name(&images.name);
+
+    let run = || -> Result<Outcome> {
+        let (environment, issues) = Environment::settle(baseline, compression::available_cpus());
+        check_environment(&name, cli, &issues)?;
 
-    for (idx, compression) in COMPRESSION.iter().enumerate() {
-        log::debug!("preset_stats: idx={idx}, compression={compression:?}");
+        let tool = tools
+            .get(compression.tool)
+            .map_err(|reason| anyhow!("{}: {reason}", compression.tool))?;
+        let (target_image, destination) = images.outputs(job);
+        log::debug!("run_job: target_image={}", target_image.display());
 
-        for (tag, img) in [("img", &image), ("uki", &uki)] {
-            let target_image = with_extension(img, &format!(".{idx}"));
-            log::debug!("preset_stats: target_image={}", target_image.display());
+        let files = workdir.files_for(&target_image, compression.extension)?;
+        let outcome = benchmark_image(cli, job, images, tool, &files, environment);
+        if let Err(error) = workdir.collect(&files, &destination) {
+            log::warn!("{name}: {error:#}");
+        }
+        Ok(outcome)
+    };
+    let outcome = run().unwrap_or_else(|error| {
+        log::error!("{name}: {} failed: {error:#}", Phase::Setup);
+        Outcome::failed(name.clone(), Failure::new(Phase::Setup, &error), None)
+    });
+
+    match &outcome {
+        Outcome::Measured(benchmark) => {
+            log::info!("{}: Environment: {}", benchmark.name, benchmark.environment);
+            log::info!("{}: Cache: {}", benchmark.name, benchmark.cache);
+            log::info!("{}: Schedule: {}", benchmark.name, benchmark.schedule);
+            log_digests(benchmark);
+            log_header(benchmark, cli.memory_limit);
+            log_size(benchmark);
+            export(timeline_dir, &benchmark.name, Phase::Compress, &benchmark.compress);
+            export(timeline_dir, &benchmark.name, Phase::Decompress, &benchmark.decompress);
+            if let Some(BootDecompress { result: Ok(stats), .. }) = &benchmark.boot {
+                export(timeline_dir, &benchmark.name, Phase::BootDecompress, stats);
+            }
+        }
+        Outcome::TimedOut { name, phase, error, .. } => export(timeline_dir, name, *phase, &error.stats),
+        Outcome::Failed { .. } | Outcome::Skipped { .. } => (),
+    }
+    outcome
+}
+
+/// Create the directory for this run, copy the log into it and remove old runs.
+///
+/// # Errors
+///
+/// Could not create the directory.
+fn create_run_dir(cli: &Cli, outdir: &Path, started: SystemTime, log: &LogTee) -> Result<RunDir> {
+    let run_dir = RunDir::create(outdir, started)?;
+    if let Err(error) = log.open(&run_dir.path().join(rundir::LOG_FILE)) {
+        log::warn!("log file: {error:#}");
+    }
+    if let Err(error) = run_dir.link_latest() {
+        log::warn!("run directory: {error:#}");
+    }
+    if let Some(keep) = cli.keep
+        && let Err(error) = rundir::prune(outdir, keep)
+    {
+        log::warn!("run directory: {error:#}");
+    }
+    Ok(run_dir)
+}
 
-            std::fs::copy(&image, &target_image)?;
-            let stats = (compression.compress)(&target_image)?;
-            log_stats(&format!("{name}/{}/{tag}/c", compression.name), &stats);
+/// Compare each pair of methods for each preset and target, when benchmarks are repeated.
+fn log_methods_significance(cli: &Cli, presets: &[PresetImages], results: &[(Job, Outcome)]) {
+    if cli.repeat.get() < 2 {
+        log::debug!("significance: skipped, needs a --repeat of at least 2");
+        return;
+    }
+    let min_samples = cli.significance.min_samples();
+    if usize::try_from(cli.repeat.get()).is_ok_and(|repeat| repeat < min_samples) {
+        log::warn!("significance: skipped, {} needs a --repeat of at least {min_samples}", cli.significance);
+        return;
+    }
 
-            std::fs::remove_file(&target_image)?;
-            let stats = (compression.decompress)(&with_extension(&target_image, compression.extension))?;
-            log_stats(&format!("{name}/{}/{tag}/d", compression.name), &stats);
+    let mut scenarios: BTreeMap<_, BTreeMap<_, (String, Vec<_>)>> = BTreeMap::new();
+    for (job, outcome) in results {
+        if let Outcome::Measured(benchmark) = outcome {
+            // a lossy round trip is a failure, not a timing
+            if !benchmark.is_lossless() {
+                continue;
+            }
+            scenarios
+                .entry((job.preset, job.target))
+                .or_default()
+                .entry((job.compression, job.sweep))
+                .or_insert_with(|| (job.method(), Vec::new()))
+                .1
+                .push(benchmark.as_ref());
         }
     }
+    for ((preset, target), methods) in scenarios {
+        let methods: Vec<_> = methods.into_values().collect();
+        log_significance(&format!("{}/{target}", presets[preset].name), &methods, cli.significance, cli.outliers());
+    }
+}
+
+/// Add the results of the run to the history in the output directory, only reporting errors.
+///
+/// Failed benchmarks are left out, including those whose decompressed image differs.
+fn append_history(
+    run_dir: &RunDir,
+    started: SystemTime,
+    seed: u64,
+    presets: &[PresetImages],
+    results: &[(Job, Outcome)],
+) {
+    let Some(outdir) = run_dir.path().parent() else {
+        return;
+    };
+    let run = history::Run {
+        id: run_dir.id(),
+        started: Manifest::timestamp(started),
+        seed,
+        results: results
+            .iter()
+            .filter_map(|(job, outcome)| match outcome {
+                Outcome::Measured(benchmark) if benchmark.is_lossless() => {
+                    Some(Record::new(job, &presets[job.preset].name, benchmark))
+                }
+                Outcome::Measured(_) | Outcome::Failed { .. } | Outcome::TimedOut { .. } | Outcome::Skipped { .. } => {
+                    None
+                }
+            })
+            .collect(),
+    };
+    match run.append_to(outdir) {
+        Ok(()) => log::info!("history: {}", outdir.join(history::HISTORY_FILE).display()),
+        Err(error) => log::warn!("history: {error:#}"),
+    }
+}
+
+/// Display the changes between two runs of the history in the output directory.
+///
+/// # Errors
+///
+/// Could not read the history, or the runs were not found.
+fn compare(cli: &Cli, old: Option<&str>, new: Option<&str>) -> Result<ExitCode> {
+    let runs = history::load(&cli.outdir)?;
+    let (old, new) = history::select(&runs, old, new)?;
+    println!("{} ({}) -> {} ({})", old.id, old.started, new.id, new.started);
+    let deltas = history::compare(old, new, cli.significance, cli.outliers());
+    for delta in &deltas {
+        println!("{delta}");
+    }
+    if deltas
+        .iter()
+        .any(|delta| delta.old.is_some() && delta.new.is_some() && delta.compress.is_none())
+    {
+        log::warn!(
+            "significance: not tested in some scenarios, {} needs at least {} repetitions in both runs",
+            cli.significance,
+            cli.significance.min_samples(),
+        );
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Write the manifest of the run, only reporting errors.
+fn write_manifest(
+    outdir: &Path,
+    started: SystemTime,
+    seed: u64,
+    environment: &Environment,
+    tools: &Tools,
+    presets: &[PresetImages],
+    config_files: Vec<PathBuf>,
+) {
+    let manifest = Manifest {
+        version: env!("CARGO_PKG_VERSION"),
+        started: Manifest::timestamp(started),
+        arguments: std::env::args().skip(1).collect(),
+        seed,
+        system: System::read(),
+        environment: environment.clone(),
+        mkinitcpio: tools::version(Path::new(mkinitcpio::MKINITCPIO)).map(String::from),
+        tools: Manifest::tool_versions(tools),
+        kernels: presets
+            .iter()
+            .filter_map(|images| Some((images.name.clone(), images.release.as_deref()?.to_owned())))
+            .collect(),
+        files: Manifest::hash_files(config_files),
+    };
+    match manifest.write_to(outdir) {
+        Ok(path) => log::info!("manifest: {}", path.display()),
+        Err(error) => log::warn!("manifest: {error:#}"),
+    }
+}
+
+/// Check that the images of all jobs fit in the output directory.
+///
+/// # Errors
+///
+/// Not enough free space, or could not read the raw images.
+fn check_disk_space(cli: &Cli, outdir: &Path, presets: &[PresetImages], plan: &Plan) -> Result<()> {
+    let jobs = plan
+        .jobs
+        .iter()
+        .map(|job| {
+            let raw = file_size(presets[job.preset].path(job.target))?.as_u64();
+            Ok(JobSpace {
+                key: (job.preset, job.target),
+                raw,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let required = cli.keep_artifacts.required_space(&jobs, cli.workdir.is_none());
+    artifacts::check_space(outdir, required)
+}
+
+/// Warn about conditions that may disturb the measurements.
+///
+/// # Errors
+///
+/// Some condition was found and `--strict` was given.
+fn check_environment(name: &str, cli: &Cli, issues: &[String]) -> Result<()> {
+    for issue in issues {
+        log::warn!("{name}: noisy environment, {issue}");
+    }
+    if cli.strict && !issues.is_empty() {
+        bail!("noisy environment: {}", issues.join(", "));
+    }
     Ok(())
 }
 
+/// Write the timeline of a command, only warning on errors.
+fn export(timeline_dir: &Path, name: &str, phase: Phase, stats: &Stats) {
+    if let Err(error) = export_timeline(timeline_dir, name, phase, stats) {
+        log::warn!("{name}: could not export {phase} timeline: {error:#}");
+    }
+}
+
+/// Compress and decompress a copy of the raw image for a job, verifying the round-trip.
+///
+/// Errors are kept in the outcome, with the phase where they happened.
+fn benchmark_image(
+    cli: &Cli,
+    job: &Job,
+    images: &PresetImages,
+    tool: &Tool,
+    files: &WorkFiles,
+    environment: Environment,
+) -> Outcome {
+    let name = job.name(&images.name);
+    let image = images.path(job.target);
+    let compression = &COMPRESSION[job.compression];
+    let threads = job.threads;
+    let cpus = compression::usable_cpus(&cli.schedule());
+
+    let mut compress_stats = None;
+    let run = || -> Result<Benchmark, Failure> {
+        let (target_image, compressed_image) = (files.image.as_path(), files.compressed.as_path());
+        std::fs::copy(image, target_image).map_err(|error| Failure::new(Phase::Setup, &error.into()))?;
+        let original = Digest::of_file(target_image).map_err(Failure::at(Phase::Setup))?;
+        let original_size = file_size(target_image).map_err(Failure::at(Phase::Setup))?;
+
+        cli.cache.prepare(target_image).map_err(Failure::at(Phase::Setup))?;
+        let compress = files
+            .compress(compression, tool, threads, &cli.measure_options(cli.compress_timeout))
+            .map_err(Failure::at(Phase::Compress))?;
+        log_stats(&format!("{name}/c"), &compress, Some(compression.threads(threads, cpus)));
+        compress_stats = Some(Box::new(compress.clone()));
+        cli.cache.release(compressed_image).map_err(Failure::at(Phase::Setup))?;
+
+        let header = FrameHeader::read(compressed_image).map_err(Failure::at(Phase::Verify))?;
+        let compressed_size = file_size(compressed_image).map_err(Failure::at(Phase::Verify))?;
+
+        files.clear_image().map_err(Failure::at(Phase::Setup))?;
+        cli.cache.prepare(compressed_image).map_err(Failure::at(Phase::Setup))?;
+        let decompress = files
+            .decompress(compression, tool, &cli.measure_options(cli.decompress_timeout))
+            .map_err(Failure::at(Phase::Decompress))?;
+        // thread count is up to the tool
+        log_stats(&format!("{name}/d"), &decompress, None);
+        cli.cache.release(target_image).map_err(Failure::at(Phase::Setup))?;
+        let roundtrip = Digest::of_file(target_image).map_err(Failure::at(Phase::Verify))?;
+
+        let limits = cli.boot_limits();
+        let boot = if limits.is_unlimited() {
+            None
+        } else {
+            files.clear_image().map_err(Failure::at(Phase::Setup))?;
+            cli.cache.prepare(compressed_image).map_err(Failure::at(Phase::Setup))?;
+            let options = measure::Options {
+                limits: limits.clone(),
+                ..cli.measure_options(cli.decompress_timeout)
+            };
+            let result = files
+                .decompress(compression, tool, &options)
+                .map_err(Failure::at(Phase::BootDecompress))
+                .and_then(|stats| {
+                    // truncated or corrupt output is not a valid timing
+                    let boot = Digest::of_file(target_image).map_err(Failure::at(Phase::Verify))?;
+                    if boot != original {
+                        let error = anyhow!("decompressed image differs (original={original}, boot={boot})");
+                        return Err(Failure::new(Phase::Verify, &error));
+                    }
+                    Ok(stats)
+                });
+            match &result {
+                Ok(stats) => log_stats(&format!("{name}/b"), stats, None),
+                Err(failure) => log::warn!("{name}: {} failed under {limits}: {}", failure.phase, failure.message),
+            }
+            Some(BootDecompress { limits, result })
+        };
+
+        Ok(Benchmark {
+            name: name.clone(),
+            cache: cli.cache,
+            schedule: cli.schedule(),
+            threads: compression.threads(threads, cpus),
+            environment,
+            compress,
+            decompress,
+            header,
+            original_size,
+            compressed_size,
+            original,
+            roundtrip,
+            boot,
+        })
+    };
+
+    match run() {
+        Ok(benchmark) => Outcome::Measured(Box::new(benchmark)),
+        Err(failure) => {
+            log::error!("{name}: {} failed: {}", failure.phase, failure.message);
+            Outcome::failed(name, failure, compress_stats)
+        }
+    }
+}
+
+/// Load the build configuration for the kernel targeted by a preset.
+///
+/// Errors and missing configurations are only reported, since all methods can still be tested.
+fn load_kernel_config(name: &str, release: &str) -> Option<KernelConfig> {
+    match KernelConfig::load(release) {
+        Ok(Some(config)) => {
+            log::debug!("{name}: kernel={release}, config={}", config.path().display());
+            Some(config)
+        }
+        Ok(None) => {
+            log::warn!("{name}: no build configuration found for kernel {release}, all methods will be tested");
+            None
+        }
+        Err(error) => {
+            log::warn!("{name}: could not load configuration for kernel {release}: {error:#}");
+            None
+        }
+    }
+}
+
+/// Size of a file, with context.
+fn file_size(path: &Path) -> Result<Byte> {
+    let metadata = std::fs::metadata(path).with_context(|| format!("could not read {}", path.display()))?;
+    Ok(Byte::from_u64(metadata.len()))
+}
+
 /// Adds string to path.
 fn with_extension(path: &Path, extension: &str) -> PathBuf {
     let mut buf = path.as_os_str().to_owned();
     buf.push(extension);
     buf.into()
 }
-
-/// Display statistics.
-fn log_stats(name: &str, stats: &Stats) {
-    log::info!("{name}: Real time: {:?}", stats.real_time());
-    log::info!(
-        "{name}: Virtual time: {:?} (usr: {:?}) (sys: {:?})",
-        stats.virtual_time(),
-        stats.user_time(),
-        stats.system_time()
-    );
-    log::info!("{name}: Maximum memory: {}", stats.max_rss().get_appropriate_unit(UnitType::Decimal));
-    log::info!("{name}: Page faults: (minor={}, major={})", stats.minor_page_faults(), stats.major_page_faults());
-    log::info!("{name}: Block operations: (input={}, output={})", stats.input_blocked(), stats.output_blocked());
-    log::info!(
-        "{name}: Context switches: (voluntary={}, involuntary={})",
-        stats.num_vol_ctx_sw(),
-        stats.num_inv_ctx_sw()
-    );
-}
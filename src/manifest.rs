@@ -0,0 +1,221 @@
+//! Description of the machine, tools and configuration of a run.
+//!
+//! Saved as `manifest.json` in the output directory, so results from different machines or dates can be compared.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::compression;
+use crate::environment::Environment;
+use crate::tools::Tools;
+use crate::utils::digest::Digest;
+
+/// Name of the manifest file in the output directory.
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+/// Hardware and kernel of the machine.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct System {
+    /// Network name of the machine.
+    pub hostname: Option<String>,
+    /// Model of the first CPU, from `/proc/cpuinfo`.
+    pub cpu_model: Option<String>,
+    /// Logical CPUs in the system.
+    pub cpus: usize,
+    /// CPUs this process may run on.
+    pub available_cpus: u32,
+    /// Total memory, in bytes.
+    pub memory_bytes: Option<u64>,
+    /// Release of the running kernel.
+    pub kernel_release: Option<String>,
+}
+
+impl System {
+    /// Read from `/proc`.
+    #[must_use]
+    pub fn read() -> Self {
+        let read = |path: &str| {
+            std::fs::read_to_string(path)
+                .inspect_err(|error| log::debug!("manifest: path={path}, error={error}"))
+                .ok()
+        };
+        let cpuinfo = read("/proc/cpuinfo").unwrap_or_default();
+
+        Self {
+            hostname: read("/proc/sys/kernel/hostname").map(|name| name.trim().to_owned()),
+            cpu_model: cpu_model(&cpuinfo),
+            cpus: cpuinfo.lines().filter(|line| line.starts_with("processor")).count(),
+            available_cpus: compression::available_cpus().get(),
+            memory_bytes: read("/proc/meminfo").and_then(|meminfo| memory_total(&meminfo)),
+            kernel_release: read("/proc/sys/kernel/osrelease").map(|release| release.trim().to_owned()),
+        }
+    }
+}
+
+/// An external binary and its version.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ToolVersion {
+    /// Resolved path.
+    pub path: PathBuf,
+    /// First line of `--version`.
+    pub version: Option<String>,
+}
+
+/// Hash of a configuration file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FileHash {
+    /// Path of the file.
+    pub path: PathBuf,
+    /// SHA-256 of the contents, as hex.
+    pub sha256: String,
+}
+
+/// Context for all results of a run.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Manifest {
+    /// Version of this benchmark.
+    pub version: &'static str,
+    /// When the run started, in RFC 3339.
+    pub started: String,
+    /// Command line arguments.
+    pub arguments: Vec<String>,
+    /// Seed for the order of the benchmarks.
+    pub seed: u64,
+    /// Machine where the run happened.
+    pub system: System,
+    /// Noise readings on the idle machine, before the run.
+    pub environment: Environment,
+    /// Version of `mkinitcpio`.
+    pub mkinitcpio: Option<String>,
+    /// Compression tools, by name.
+    pub tools: BTreeMap<String, ToolVersion>,
+    /// Kernel release targeted by each preset.
+    pub kernels: BTreeMap<String, String>,
+    /// Hashes of the preset and configuration files.
+    pub files: Vec<FileHash>,
+}
+
+impl Manifest {
+    /// Hash the given configuration files, skipping unreadable ones.
+    #[must_use]
+    pub fn hash_files(paths: impl IntoIterator<Item = PathBuf>) -> Vec<FileHash> {
+        let mut files: Vec<_> = paths
+            .into_iter()
+            .filter_map(|path| match Digest::of_file(&path) {
+                Ok(digest) => Some(FileHash {
+                    sha256: digest.to_string(),
+                    path,
+                }),
+                Err(error) => {
+                    log::debug!("manifest: {error:#}");
+                    None
+                }
+            })
+            .collect();
+        files.sort_unstable_by(|a, b| a.path.cmp(&b.path));
+        files.dedup();
+        files
+    }
+
+    /// Versions of all discovered tools.
+    #[must_use]
+    pub fn tool_versions(tools: &Tools) -> BTreeMap<String, ToolVersion> {
+        tools
+            .iter()
+            .map(|tool| {
+                let version = ToolVersion {
+                    path: tool.path.clone(),
+                    version: tool.version.as_deref().map(str::to_owned),
+                };
+                (tool.name.to_string(), version)
+            })
+            .collect()
+    }
+
+    /// Format a time for [`started`](Self::started), in RFC 3339.
+    #[must_use]
+    pub fn timestamp(time: SystemTime) -> String {
+        humantime::format_rfc3339_seconds(time).to_string()
+    }
+
+    /// Write as pretty JSON to `dir/manifest.json`.
+    ///
+    /// # Errors
+    ///
+    /// Could not create or write the file.
+    pub fn write_to(&self, dir: &Path) -> Result<PathBuf> {
+        let path = dir.join(MANIFEST_FILE);
+        let json = serde_json::to_string_pretty(self).context("could not serialize manifest")?;
+        std::fs::write(&path, json + "\n").with_context(|| format!("could not write {}", path.display()))?;
+        Ok(path)
+    }
+}
+
+/// Model name of the first CPU, from the contents of `/proc/cpuinfo`.
+fn cpu_model(cpuinfo: &str) -> Option<String> {
+    cpuinfo.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        matches!(key.trim(), "model name" | "Model" | "cpu model").then(|| value.trim().to_owned())
+    })
+}
+
+/// Total memory in bytes, from the contents of `/proc/meminfo`.
+fn memory_total(meminfo: &str) -> Option<u64> {
+    let line = meminfo.lines().find_map(|line| line.strip_prefix("MemTotal:"))?;
+    let kibibytes: u64 = line.trim().strip_suffix("kB")?.trim().parse().ok()?;
+    kibibytes.checked_mul(1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use test_log::test;
+
+    use super::*;
+
+    #[test]
+    fn parses_proc_files() {
+        let cpuinfo = "processor\t: 0\nvendor_id\t: AuthenticAMD\nmodel name\t: AMD Ryzen 7 5800X 8-Core Processor\n\n\
+                       processor\t: 1\nmodel name\t: AMD Ryzen 7 5800X 8-Core Processor\n";
+        assert_eq!(cpu_model(cpuinfo).as_deref(), Some("AMD Ryzen 7 5800X 8-Core Processor"));
+        assert_eq!(cpu_model(""), None);
+
+        let meminfo = "MemTotal:       32768000 kB\nMemFree:         1000000 kB\n";
+        assert_eq!(memory_total(meminfo), Some(32_768_000 * 1024));
+        assert_eq!(memory_total("MemFree: 1 kB\n"), None);
+    }
+
+    #[test]
+    fn writes_json() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = dir.path().join("mkinitcpio.conf");
+        std::fs::write(&config, "HOOKS=(base)\n").unwrap();
+
+        let manifest = Manifest {
+            version: env!("CARGO_PKG_VERSION"),
+            started: Manifest::timestamp(SystemTime::now()),
+            arguments: vec!["--repeat=2".into()],
+            seed: 42,
+            system: System::read(),
+            environment: Environment::default(),
+            mkinitcpio: None,
+            tools: BTreeMap::new(),
+            kernels: BTreeMap::from([("linux".into(), "6.12.1-arch1-1".into())]),
+            files: Manifest::hash_files([config.clone(), dir.path().join("missing")]),
+        };
+        assert_eq!(manifest.files.len(), 1, "missing files are skipped");
+        assert_eq!(manifest.files[0].sha256, Digest::of_bytes("HOOKS=(base)\n").to_string());
+        assert!(manifest.system.available_cpus > 0, "at least one CPU");
+
+        let path = manifest.write_to(dir.path()).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+        assert_eq!(json["seed"], 42);
+        assert_eq!(json["environment"]["load"], serde_json::Value::Null);
+        assert_eq!(json["kernels"]["linux"], "6.12.1-arch1-1");
+        assert_eq!(json["files"][0]["path"], config.display().to_string());
+    }
+}
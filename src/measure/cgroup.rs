@@ -0,0 +1,581 @@
+//! Transient cgroup v2 groups, for measuring whole process trees.
+//!
+//! See [Control Group v2](https://docs.kernel.org/admin-guide/cgroup-v2.html).
+
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, ErrorKind};
+use std::num::NonZeroU32;
+use std::os::fd::AsRawFd;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+
+use anyhow::{Context, Result, bail};
+use byte_unit::{Byte, UnitType};
+use hashbrown::HashMap;
+
+use super::schedule::CpuList;
+
+/// Controllers enabled for the transient groups, when available.
+const CONTROLLERS: &[&str] = &["cpu", "cpuset", "io", "memory"];
+
+/// Period for the `cpu.max` bandwidth limit, in microseconds.
+const CPU_MAX_PERIOD: u64 = 100_000;
+
+/// Attempts to remove a group while its last processes are being reaped.
+const REMOVE_ATTEMPTS: u32 = 50;
+
+/// Leaf group this process moves into, so controllers can be enabled for the transient groups.
+const SUPERVISOR: &str = "supervisor";
+
+/// Counter for unique group names inside this process.
+static NEXT_ID: AtomicU32 = AtomicU32::new(0);
+
+/// Parent of the transient groups, set up on first use.
+static PARENT: Mutex<Option<Parent>> = Mutex::new(None);
+
+/// Resource usage of all processes that ran in a cgroup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CgroupStats {
+    /// Maximum memory usage, from `memory.peak`.
+    ///
+    /// Only with the `memory` controller, since Linux 5.19.
+    pub memory_peak: Option<Byte>,
+    /// CPU usage, from `cpu.stat`.
+    pub cpu: CpuStat,
+    /// Block device usage, from `io.stat`.
+    ///
+    /// Only with the `io` controller.
+    pub io: Option<IoStat>,
+    /// Processes killed for going over `memory.max`, from `memory.events`.
+    ///
+    /// Only with the `memory` controller.
+    pub oom_kills: Option<u64>,
+}
+
+/// Resource limits for a group, like the ones found by the kernel in early boot.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Limits {
+    /// CPU bandwidth, as a number of CPUs, with `cpu.max`.
+    pub cpus: Option<NonZeroU32>,
+    /// CPUs allowed to run the processes, with `cpuset.cpus`.
+    pub cpuset: Option<CpuList>,
+    /// Memory limit, with `memory.max`. Swap is disabled for the group as well.
+    pub memory: Option<Byte>,
+}
+
+impl Limits {
+    /// No limit is set.
+    #[must_use]
+    pub const fn is_unlimited(&self) -> bool {
+        self.cpus.is_none() && self.cpuset.is_none() && self.memory.is_none()
+    }
+
+    /// Make sure the limits can be enforced, by creating a group with them, if any.
+    ///
+    /// # Errors
+    ///
+    /// No cgroup2 filesystem, or a controller for a limit is not delegated to the group of this process.
+    pub fn check(&self) -> Result<()> {
+        if self.is_unlimited() {
+            return Ok(());
+        }
+        let cgroup = Cgroup::create(&Self::default())?;
+        let parent = cgroup.path.parent().unwrap_or(&cgroup.path);
+        let enabled = std::fs::read_to_string(parent.join("cgroup.subtree_control"))
+            .with_context(|| format!("could not read controllers of cgroup {}", parent.display()))?;
+        let missing: Vec<_> = self
+            .controllers()
+            .filter(|controller| !enabled.split_whitespace().any(|name| name == *controller))
+            .collect();
+        if !missing.is_empty() {
+            bail!("controllers not available in cgroup {}: {}", parent.display(), missing.join(" "));
+        }
+        self.apply(&cgroup.path)
+    }
+
+    /// Controllers needed for the limits.
+    fn controllers(&self) -> impl Iterator<Item = &'static str> {
+        [
+            self.cpus.map(|_| "cpu"),
+            self.cpuset.as_ref().map(|_| "cpuset"),
+            self.memory.map(|_| "memory"),
+        ]
+        .into_iter()
+        .flatten()
+    }
+
+    /// Write the limits to the interface files of the group at `path`.
+    fn apply(&self, path: &Path) -> Result<()> {
+        let write = |file: &str, value: String| {
+            log::debug!("cgroup: path={}, {file}={value}", path.display());
+            std::fs::write(path.join(file), value)
+                .with_context(|| format!("could not set {file} in cgroup {}", path.display()))
+        };
+
+        if let Some(cpus) = self.cpus {
+            let quota = CPU_MAX_PERIOD.saturating_mul(cpus.get().into());
+            write("cpu.max", format!("{quota} {CPU_MAX_PERIOD}"))?;
+        }
+        if let Some(cpuset) = &self.cpuset {
+            write("cpuset.cpus", cpuset.to_string())?;
+        }
+        if let Some(memory) = self.memory {
+            write("memory.max", memory.as_u64().to_string())?;
+            if let Err(error) = write("memory.swap.max", "0".into()) {
+                log::debug!("{error:#}");
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Limits {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_unlimited() {
+            return f.write_str("unlimited");
+        }
+
+        let mut separator = "";
+        if let Some(cpus) = self.cpus {
+            write!(f, "{separator}cpus={cpus}")?;
+            separator = ", ";
+        }
+        if let Some(cpuset) = &self.cpuset {
+            write!(f, "{separator}cpuset={cpuset}")?;
+            separator = ", ";
+        }
+        if let Some(memory) = self.memory {
+            write!(f, "{separator}memory={}", memory.get_appropriate_unit(UnitType::Binary))?;
+        }
+        Ok(())
+    }
+}
+
+/// CPU usage and throttling, from `cpu.stat`.
+///
+/// Throttling is zero without the `cpu` controller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CpuStat {
+    /// Total CPU time.
+    pub usage: Duration,
+    /// User CPU time.
+    pub user: Duration,
+    /// System CPU time.
+    pub system: Duration,
+    /// Enforcement periods elapsed.
+    pub periods: u64,
+    /// Periods where the group was throttled.
+    pub throttled_periods: u64,
+    /// Time spent throttled.
+    pub throttled: Duration,
+}
+
+impl CpuStat {
+    /// Parse the contents of `cpu.stat`.
+    fn parse(content: &str) -> Self {
+        let values: HashMap<_, _> = content
+            .lines()
+            .filter_map(|line| line.split_once(' '))
+            .filter_map(|(key, value)| Some((key, value.trim().parse::<u64>().ok()?)))
+            .collect();
+        let value = |key: &str| values.get(key).copied().unwrap_or(0);
+
+        Self {
+            usage: Duration::from_micros(value("usage_usec")),
+            user: Duration::from_micros(value("user_usec")),
+            system: Duration::from_micros(value("system_usec")),
+            periods: value("nr_periods"),
+            throttled_periods: value("nr_throttled"),
+            throttled: Duration::from_micros(value("throttled_usec")),
+        }
+    }
+}
+
+/// Block device usage summed over all devices, from `io.stat`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct IoStat {
+    /// Bytes read.
+    pub read_bytes: Byte,
+    /// Bytes written.
+    pub write_bytes: Byte,
+    /// Read operations.
+    pub read_ios: u64,
+    /// Write operations.
+    pub write_ios: u64,
+}
+
+impl IoStat {
+    /// Parse the contents of `io.stat`, with one line per device.
+    fn parse(content: &str) -> Self {
+        let (mut read_bytes, mut write_bytes, mut read_ios, mut write_ios) = (0_u64, 0_u64, 0_u64, 0_u64);
+        for line in content.lines() {
+            for (key, value) in line
+                .split_whitespace()
+                .skip(1)
+                .filter_map(|field| field.split_once('='))
+            {
+                let Ok(value) = value.parse::<u64>() else {
+                    continue;
+                };
+                match key {
+                    "rbytes" => read_bytes = read_bytes.saturating_add(value),
+                    "wbytes" => write_bytes = write_bytes.saturating_add(value),
+                    "rios" => read_ios = read_ios.saturating_add(value),
+                    "wios" => write_ios = write_ios.saturating_add(value),
+                    _ => (),
+                }
+            }
+        }
+
+        Self {
+            read_bytes: Byte::from_u64(read_bytes),
+            write_bytes: Byte::from_u64(write_bytes),
+            read_ios,
+            write_ios,
+        }
+    }
+}
+
+/// A transient cgroup for a single command, removed on drop.
+#[derive(Debug)]
+pub struct Cgroup {
+    /// Directory of the group in the cgroup2 filesystem.
+    path: PathBuf,
+    /// Opened `cgroup.procs`, so the child can join without allocating.
+    procs: File,
+}
+
+impl Cgroup {
+    /// Create an empty group under the [`Parent`] of this process, with some resource limits.
+    ///
+    /// # Errors
+    ///
+    /// No cgroup2 filesystem mounted, no permission to create groups, or the controller for a limit is not
+    /// available.
+    pub fn create(limits: &Limits) -> Result<Self> {
+        let mut parent = PARENT.lock().unwrap_or_else(std::sync::PoisonError::into_inner);
+        let parent_path = match &*parent {
+            Some(parent) => parent.path.clone(),
+            None => parent.insert(Parent::create()?).path.clone(),
+        };
+        drop(parent);
+
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let path = parent_path.join(id.to_string());
+        std::fs::create_dir_all(&path).with_context(|| format!("could not create cgroup {}", path.display()))?;
+
+        let procs = OpenOptions::new().write(true).open(path.join("cgroup.procs"));
+        let cgroup = Self {
+            procs: procs.with_context(|| format!("could not open cgroup {}", path.display()))?,
+            path,
+        };
+        limits.apply(&cgroup.path)?;
+        log::debug!("cgroup: created {}", cgroup.path.display());
+        Ok(cgroup)
+    }
+
+    /// Move the command into this group, between `fork` and `exec`.
+    ///
+    /// All descendants of the command stay in the group.
+    pub fn attach(&self, command: &mut Command) {
+        let fd = self.procs.as_raw_fd();
+        let join = move || {
+            // SAFETY: `write` is async-signal-safe and the buffer is valid, `fd` stays open until `exec`
+            let written = unsafe { libc::write(fd, b"0".as_ptr().cast(), 1) };
+            if written < 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(())
+            }
+        };
+        // SAFETY: the closure doesn't allocate nor take locks
+        unsafe { command.pre_exec(join) };
+    }
+
+    /// Read the resource usage of all processes that ran in the group.
+    ///
+    /// # Errors
+    ///
+    /// Could not read `cpu.stat`, which is always available.
+    pub fn stats(&self) -> Result<CgroupStats> {
+        let cpu = std::fs::read_to_string(self.path.join("cpu.stat"))
+            .with_context(|| format!("could not read cgroup {}", self.path.display()))?;
+        let memory_peak = self
+            .read_optional("memory.peak")
+            .and_then(|peak| peak.trim().parse().ok())
+            .map(Byte::from_u64);
+        let io = self.read_optional("io.stat").map(|io| IoStat::parse(&io));
+        let oom_kills = self
+            .read_optional("memory.events")
+            .map(|events| parse_oom_kills(&events));
+
+        Ok(CgroupStats {
+            memory_peak,
+            cpu: CpuStat::parse(&cpu),
+            io,
+            oom_kills,
+        })
+    }
+
+    /// Read an interface file that depends on a controller.
+    fn read_optional(&self, name: &str) -> Option<String> {
+        std::fs::read_to_string(self.path.join(name))
+            .inspect_err(|error| log::debug!("cgroup: path={}, file={name}, error={error}", self.path.display()))
+            .ok()
+    }
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        // leftover daemons would keep the group busy
+        if self
+            .read_optional("cgroup.events")
+            .is_some_and(|events| events.contains("populated 1"))
+            && let Err(error) = std::fs::write(self.path.join("cgroup.kill"), "1")
+        {
+            log::warn!("cgroup: could not kill {}: {error}", self.path.display());
+        }
+
+        for _ in 0..REMOVE_ATTEMPTS {
+            match std::fs::remove_dir(&self.path) {
+                Ok(()) => {
+                    log::debug!("cgroup: removed {}", self.path.display());
+                    return;
+                }
+                Err(error) if error.kind() == ErrorKind::ResourceBusy => {
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+                Err(error) => {
+                    log::warn!("cgroup: could not remove {}: {error}", self.path.display());
+                    return;
+                }
+            }
+        }
+        log::warn!("cgroup: could not remove {}: still busy", self.path.display());
+    }
+}
+
+/// Group holding the transient groups, under the group this process started in.
+///
+/// Controllers can only be enabled for the children of a group without processes, so this process moves into a
+/// [`SUPERVISOR`] leaf next to the transient groups. Nothing is changed at the cgroup2 root, and everything is
+/// undone by [`release`].
+#[derive(Debug)]
+struct Parent {
+    /// Group this process started in.
+    origin: PathBuf,
+    /// Whether `origin` is the cgroup2 root, whose controllers are left alone.
+    at_root: bool,
+    /// Directory of the group, named after this process.
+    path: PathBuf,
+    /// Controllers enabled in `origin`, disabled again on release.
+    enabled: Vec<&'static str>,
+}
+
+impl Parent {
+    /// Create the group and move this process into its [`SUPERVISOR`] leaf.
+    ///
+    /// Failures to enable controllers are only logged, since `cpu.stat` is always available.
+    fn create() -> Result<Self> {
+        let mounts = std::fs::read_to_string("/proc/self/mounts").context("could not read mounts")?;
+        let root = mount_point(&mounts).context("no cgroup2 filesystem mounted")?;
+        let membership = std::fs::read_to_string("/proc/self/cgroup").context("could not read own cgroup")?;
+        let origin = own_group(&root, &membership).context("not in a cgroup2 group")?;
+
+        let path = origin.join(format!("mkinitcpio-compression-benchmark-{}", std::process::id()));
+        let supervisor = path.join(SUPERVISOR);
+        std::fs::create_dir_all(&supervisor)
+            .with_context(|| format!("could not create cgroup {}", supervisor.display()))?;
+        let mut parent = Self {
+            at_root: origin == root,
+            origin,
+            path,
+            enabled: Vec::new(),
+        };
+
+        if parent.at_root {
+            log::debug!("cgroup: not enabling controllers at the root {}", root.display());
+        } else {
+            match move_to(&supervisor) {
+                Ok(()) => parent.enabled = enable_controllers(&parent.origin),
+                Err(error) => log::debug!("{error:#}"),
+            }
+        }
+        enable_controllers(&parent.path);
+        log::debug!("cgroup: parent {}, enabled={:?}", parent.path.display(), parent.enabled);
+        Ok(parent)
+    }
+}
+
+impl Drop for Parent {
+    fn drop(&mut self) {
+        // controllers are disabled bottom-up, and processes can only join a group with none enabled
+        let enabled = std::fs::read_to_string(self.path.join("cgroup.subtree_control")).unwrap_or_default();
+        disable_controllers(&self.path, enabled.split_whitespace());
+        disable_controllers(&self.origin, self.enabled.iter().copied());
+        if !self.at_root
+            && let Err(error) = move_to(&self.origin)
+        {
+            log::warn!("{error:#}");
+        }
+
+        for path in [self.path.join(SUPERVISOR), self.path.clone()] {
+            match std::fs::remove_dir(&path) {
+                Ok(()) => log::debug!("cgroup: removed {}", path.display()),
+                Err(error) if error.kind() == ErrorKind::NotFound => (),
+                Err(error) => log::warn!("cgroup: could not remove {}: {error}", path.display()),
+            }
+        }
+    }
+}
+
+/// Remove the parent of the transient groups and move this process back to its original group.
+///
+/// Transient groups must be dropped first. A new parent is set up if another group is created afterwards.
+pub fn release() {
+    let parent = PARENT.lock().unwrap_or_else(std::sync::PoisonError::into_inner).take();
+    drop(parent);
+}
+
+/// Directory of the group of this process, from the contents of `/proc/self/cgroup`.
+fn own_group(root: &Path, membership: &str) -> Option<PathBuf> {
+    let relative = membership.lines().find_map(|line| line.strip_prefix("0::"))?;
+    Some(root.join(relative.trim().trim_start_matches('/')))
+}
+
+/// Move this process into the group at `path`.
+fn move_to(path: &Path) -> Result<()> {
+    std::fs::write(path.join("cgroup.procs"), std::process::id().to_string())
+        .with_context(|| format!("could not move into cgroup {}", path.display()))
+}
+
+/// Find where the cgroup2 filesystem is mounted, from the contents of `/proc/self/mounts`.
+fn mount_point(mounts: &str) -> Option<PathBuf> {
+    mounts.lines().find_map(|line| {
+        let mut fields = line.split_whitespace();
+        let (_, target, fstype) = (fields.next()?, fields.next()?, fields.next()?);
+        (fstype == "cgroup2").then(|| target.into())
+    })
+}
+
+/// Number of OOM kills, from the contents of `memory.events`.
+fn parse_oom_kills(events: &str) -> u64 {
+    events
+        .lines()
+        .find_map(|line| line.strip_prefix("oom_kill "))
+        .and_then(|count| count.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+/// Enable [`CONTROLLERS`] for children of the group at `path`, if available.
+///
+/// Returns the controllers that were not enabled before. Failures are only logged, since `cpu.stat` is always
+/// available.
+fn enable_controllers(path: &Path) -> Vec<&'static str> {
+    let available = std::fs::read_to_string(path.join("cgroup.controllers")).unwrap_or_default();
+    let enabled = std::fs::read_to_string(path.join("cgroup.subtree_control")).unwrap_or_default();
+
+    let mut newly_enabled = Vec::new();
+    for controller in CONTROLLERS {
+        if enabled.split_whitespace().any(|name| name == *controller) {
+            continue;
+        }
+        if !available.split_whitespace().any(|name| name == *controller) {
+            log::debug!("cgroup: controller {controller} not available in {}", path.display());
+            continue;
+        }
+        match std::fs::write(path.join("cgroup.subtree_control"), format!("+{controller}")) {
+            Ok(()) => newly_enabled.push(*controller),
+            Err(error) => log::debug!("cgroup: could not enable {controller} in {}: {error}", path.display()),
+        }
+    }
+    newly_enabled
+}
+
+/// Disable `controllers` for children of the group at `path`, only logging failures.
+fn disable_controllers<'a>(path: &Path, controllers: impl IntoIterator<Item = &'a str>) {
+    for controller in controllers {
+        if let Err(error) = std::fs::write(path.join("cgroup.subtree_control"), format!("-{controller}")) {
+            log::warn!("cgroup: could not disable {controller} in {}: {error}", path.display());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use test_log::test;
+
+    use super::*;
+
+    #[test]
+    fn finds_mount_point() {
+        let mounts = "proc /proc proc rw,nosuid,nodev,noexec,relatime 0 0\n\
+                      tmpfs /sys/fs/cgroup tmpfs rw,relatime,mode=755 0 0\n\
+                      cgroup2 /sys/fs/cgroup/unified cgroup2 rw,relatime 0 0\n";
+        assert_eq!(mount_point(mounts), Some("/sys/fs/cgroup/unified".into()));
+        assert_eq!(mount_point("proc /proc proc rw 0 0\n"), None);
+    }
+
+    #[test]
+    fn finds_own_group() {
+        let root = Path::new("/sys/fs/cgroup");
+        let membership = "1:name=systemd:/user.slice\n0::/system.slice/run-u12.service\n";
+        assert_eq!(own_group(root, membership), Some("/sys/fs/cgroup/system.slice/run-u12.service".into()));
+        assert_eq!(own_group(root, "0::/\n"), Some(root.into()));
+        assert_eq!(own_group(root, "1:name=systemd:/\n"), None, "cgroup v1 only");
+    }
+
+    #[test]
+    fn parses_cpu_stat() {
+        let stat = CpuStat::parse(
+            "usage_usec 1500\nuser_usec 1000\nsystem_usec 500\nnr_periods 10\nnr_throttled 2\nthrottled_usec 30\n",
+        );
+        assert_eq!(stat.usage, Duration::from_micros(1500));
+        assert_eq!(stat.user, Duration::from_millis(1));
+        assert_eq!(stat.system, Duration::from_micros(500));
+        assert_eq!((stat.periods, stat.throttled_periods), (10, 2));
+        assert_eq!(stat.throttled, Duration::from_micros(30));
+
+        let stat = CpuStat::parse("usage_usec 7\nuser_usec 4\nsystem_usec 3\n");
+        assert_eq!(stat.periods, 0, "no cpu controller");
+    }
+
+    #[test]
+    fn parses_io_stat() {
+        let stat = IoStat::parse(
+            "8:0 rbytes=4096 wbytes=8192 rios=1 wios=2 dbytes=0 dios=0\n\
+             259:0 rbytes=1024 wbytes=0 rios=3 wios=0 dbytes=0 dios=0\n",
+        );
+        assert_eq!(stat.read_bytes, Byte::from_u64(5120));
+        assert_eq!(stat.write_bytes, Byte::from_u64(8192));
+        assert_eq!((stat.read_ios, stat.write_ios), (4, 2));
+        assert_eq!(IoStat::parse(""), IoStat::default());
+    }
+
+    #[test]
+    fn parses_oom_kills() {
+        let events = "low 0\nhigh 0\nmax 12\noom 1\noom_kill 1\noom_group_kill 0\n";
+        assert_eq!(parse_oom_kills(events), 1);
+        assert_eq!(parse_oom_kills(""), 0);
+    }
+
+    #[test]
+    fn displays_limits() {
+        assert_eq!(Limits::default().to_string(), "unlimited");
+        let limits = Limits {
+            cpus: Some(NonZeroU32::MIN),
+            cpuset: Some("0".parse().unwrap()),
+            memory: Some(Byte::from_u64(256 << 20)),
+        };
+        assert_eq!(limits.to_string(), "cpus=1, cpuset=0, memory=256 MiB");
+        assert_eq!(limits.controllers().collect::<Vec<_>>(), ["cpu", "cpuset", "memory"]);
+        assert_eq!(Limits::default().controllers().count(), 0);
+        Limits::default().check().unwrap();
+    }
+}
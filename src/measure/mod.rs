@@ -1,41 +1,176 @@
 //! Run command and measure resource usage.
 
+use std::error::Error;
 use std::ffi::OsStr;
-use std::io::Read;
+use std::fmt;
+use std::fs::File;
 use std::os::unix::ffi::OsStrExt;
-use std::process::{Child, Command, Output};
-use std::time::{Instant, SystemTime};
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Output};
+use std::time::{Duration, Instant};
 
 use anyhow::{Context, Result};
+use byte_unit::Byte;
 use nix::errno::Errno;
+use nix::sys::wait::{Id, WaitPidFlag, waitid};
 use nix::unistd::Pid;
 
+mod cgroup;
+mod output;
+mod procfs;
+mod sampler;
+mod schedule;
+mod timeout;
 mod usage;
 
-pub use usage::Stats;
+pub use cgroup::{CgroupStats, Limits, release as release_cgroups};
+pub use procfs::ProcIo;
+pub use sampler::Timeline;
+pub use schedule::{CpuList, IoPriority, Policy, Schedule};
+pub use timeout::TimeoutError;
+pub use usage::{Stats, Termination};
+
+use self::cgroup::Cgroup;
+use self::sampler::Sampler;
+use self::timeout::Watchdog;
 
 use crate::utils::command;
 
-/// Execute command and measure resource usage.
-///
-/// Standard output and standard error are logged.
-///
-/// # Errors
-///
-/// Fails if the program exits with non-zero status, or any other runtime issue.
-pub fn exec(program: impl AsRef<OsStr>, args: impl IntoIterator<Item = impl AsRef<OsStr>>) -> Result<Stats> {
-    let (output, usage) = wait_exit(command::command(&program, args))?;
+/// Output kept for each stream by default.
+pub const DEFAULT_OUTPUT_LIMIT: Byte = Byte::MEBIBYTE;
 
-    let name = String::from_utf8_lossy(program.as_ref().as_bytes());
-    command::check(&name, output, true)?;
-    Ok(usage)
+/// How commands are run and measured.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Options {
+    /// Run each command in a transient cgroup v2, measuring all of its descendants.
+    pub cgroup: bool,
+    /// Kill the command and all processes in its group after this time.
+    pub timeout: Option<Duration>,
+    /// Maximum output kept in memory for each stream, older output is discarded.
+    pub output_limit: Byte,
+    /// Sample memory, CPU and I/O of the process at this interval, building a [`Timeline`].
+    pub sample_interval: Option<Duration>,
+    /// CPU affinity, scheduling and I/O priority.
+    pub schedule: Schedule,
+    /// Resource limits, enforced with a transient cgroup even without [`cgroup`](Self::cgroup).
+    pub limits: Limits,
 }
 
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            cgroup: false,
+            timeout: None,
+            output_limit: DEFAULT_OUTPUT_LIMIT,
+            sample_interval: None,
+            schedule: Schedule::default(),
+            limits: Limits::default(),
+        }
+    }
+}
+
+impl Options {
+    /// Execute command and measure resource usage.
+    ///
+    /// Standard output and standard error are logged as they arrive.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the program exits with non-zero status, or any other runtime issue. Commands killed by the
+    /// [`timeout`](Self::timeout) fail with a [`TimeoutError`], keeping their resource usage.
+    pub fn exec(&self, program: impl AsRef<OsStr>, args: impl IntoIterator<Item = impl AsRef<OsStr>>) -> Result<Stats> {
+        self.run(command::command(&program, args), program.as_ref())
+    }
+
+    /// Execute command with its standard input and output redirected to files, and measure resource usage.
+    ///
+    /// Standard error is logged as it arrives.
+    ///
+    /// # Errors
+    ///
+    /// Same as [`exec`](Self::exec).
+    pub fn exec_with_stdio(
+        &self,
+        program: impl AsRef<OsStr>,
+        args: impl IntoIterator<Item = impl AsRef<OsStr>>,
+        stdin: File,
+        stdout: File,
+    ) -> Result<Stats> {
+        let mut command = command::command(&program, args);
+        command.stdin(stdin).stdout(stdout);
+        self.run(command, program.as_ref())
+    }
+
+    /// Run a prepared command, turning timeouts, signals and failure status into errors.
+    fn run(&self, command: Command, program: &OsStr) -> Result<Stats> {
+        let name = String::from_utf8_lossy(program.as_bytes());
+        let (output, usage, timed_out) = wait_exit(command, &name, self)?;
+
+        if let Some(timeout) = self.timeout
+            && timed_out
+        {
+            return Err(TimeoutError {
+                name: name.into_owned(),
+                timeout,
+                stats: usage,
+            }
+            .into());
+        }
+        if let Termination::Signaled { .. } = usage.termination() {
+            return Err(SignalError {
+                name: name.into_owned(),
+                stats: usage,
+                stderr: command::stderr_excerpt(&output.stderr),
+            }
+            .into());
+        }
+
+        command::check_status(&name, &output)?;
+        Ok(usage)
+    }
+}
+
+/// A command terminated by a signal, like the OOM killer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignalError {
+    /// Name of the command.
+    pub name: String,
+    /// Resource usage until the command was killed.
+    pub stats: Stats,
+    /// Last lines from stderr, trimmed.
+    pub stderr: String,
+}
+
+impl fmt::Display for SignalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.name, self.stats.termination())?;
+        if !self.stderr.is_empty() {
+            write!(f, ": {}", self.stderr)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for SignalError {}
+
 /// Wait for process to exit, capturing its output and resource usage.
-fn wait_exit(mut command: Command) -> Result<(Output, Stats)> {
-    let wall_time = SystemTime::now();
-    let monotonic_time = Instant::now();
-    let process = command.spawn()?;
+///
+/// Also returns whether the process was killed by the timeout.
+fn wait_exit(mut command: Command, name: &str, options: &Options) -> Result<(Output, Stats, bool)> {
+    let cgroup = (options.cgroup || !options.limits.is_unlimited())
+        .then(|| Cgroup::create(&options.limits))
+        .transpose()?;
+    if let Some(cgroup) = &cgroup {
+        cgroup.attach(&mut command);
+    }
+    options.schedule.apply(&mut command)?;
+    if options.timeout.is_some() {
+        // new group, so the watchdog kills all descendants
+        command.process_group(0);
+    }
+
+    let start_time = Instant::now();
+    let mut process = command.spawn()?;
     drop(command);
 
     let pid = process
@@ -44,40 +179,66 @@ fn wait_exit(mut command: Command) -> Result<(Output, Stats)> {
         .map(Pid::from_raw)
         .with_context(|| format!("invalid PID: {}", process.id()))?;
 
-    let (stdout, stderr) = capture_output(process)?;
-    let usage = wait4(pid, wall_time, monotonic_time)?;
+    let watchdog = options
+        .timeout
+        .map(|timeout| Watchdog::start(pid, timeout))
+        .transpose()?;
+    let sampler = options
+        .sample_interval
+        .map(|interval| Sampler::start(pid, interval, start_time))
+        .transpose()?;
+
+    let limit = usize::try_from(options.output_limit.as_u64()).unwrap_or(usize::MAX);
+    let (stdout, stderr) = output::capture(&mut process, name, limit)?;
+
+    // counters are lost once the process is reaped, and its PGID may be reused
+    wait_exited(pid)?;
+    let timed_out = watchdog.is_some_and(Watchdog::stop);
+    let io = ProcIo::read(pid)
+        .inspect_err(|error| log::debug!("wait_exit: pid={pid}, error={error:#}"))
+        .ok();
+    let timeline = sampler.map(Sampler::stop);
+
+    let mut usage = wait4(pid, start_time)?;
+    if let Some(io) = io {
+        usage = usage.with_io(io);
+    }
+    if let Some(timeline) = timeline {
+        usage = usage.with_timeline(timeline);
+    }
+    if let Some(cgroup) = &cgroup {
+        usage = usage.with_cgroup(cgroup.stats()?);
+    }
 
     let output = Output {
         status: usage.exit_status(),
         stdout,
         stderr,
     };
-    Ok((output, usage))
+    Ok((output, usage, timed_out))
 }
 
-/// Capture stdout and stderr from child.
-fn capture_output(mut process: Child) -> Result<(Vec<u8>, Vec<u8>)> {
-    std::mem::drop(process.stdin.take());
-    log::trace!("capture_output: stdin");
-
-    let (mut stdout, mut stderr) = (Vec::new(), Vec::new());
-    if let Some(mut out) = process.stdout.take() {
-        out.read_to_end(&mut stdout)?;
-    }
-    log::trace!("capture_output: stdout");
-
-    if let Some(mut err) = process.stderr.take() {
-        err.read_to_end(&mut stderr)?;
+/// Wait for process to exit, without reaping it.
+///
+/// The process stays as a zombie, so `/proc/<pid>` can still be read. For more details, see
+/// [waitid(2)](https://man.archlinux.org/man/waitid.2).
+fn wait_exited(pid: Pid) -> Result<()> {
+    loop {
+        match waitid(Id::Pid(pid), WaitPidFlag::WEXITED | WaitPidFlag::WNOWAIT) {
+            Ok(status) => {
+                log::trace!("waitid: pid={pid}, status={status:?}");
+                return Ok(());
+            }
+            Err(Errno::EINTR) => log::trace!("waitid: pid={pid}, interrupted"),
+            Err(errno) => return Err(errno.into()),
+        }
     }
-    log::trace!("capture_output: stderr");
-
-    Ok((stdout, stderr))
 }
 
 /// Wait for process to exit and return its resource usage.
 ///
 /// For more details, see [wait4(2)](https://man.archlinux.org/man/wait4.2).
-fn wait4(pid: Pid, wall_time: SystemTime, monotonic_time: Instant) -> Result<Stats> {
+fn wait4(pid: Pid, start_time: Instant) -> Result<Stats> {
     log::debug!("wait4: pid={pid}, options not supported in modern Linux");
 
     let mut wstatus: i32 = 0;
@@ -89,14 +250,13 @@ fn wait4(pid: Pid, wall_time: SystemTime, monotonic_time: Instant) -> Result<Sta
     let errno = Errno::last();
 
     log::trace!("wait4: result={result}, errno={errno}, wstatus={wstatus}, usage={usage:?}");
-    let virtual_time = monotonic_time.elapsed();
-    let real_time = wall_time.elapsed()?;
-    log::trace!("wait4: real_time={real_time:?}, virtual_time={virtual_time:?}");
+    let real_time = start_time.elapsed();
+    log::trace!("wait4: real_time={real_time:?}");
 
     if result == -1 {
         return Err(errno.into());
     }
-    Stats::from_result(pid, result, wstatus, usage, real_time, virtual_time)
+    Stats::from_result(pid, result, wstatus, usage, real_time)
 }
 
 #[cfg(test)]
@@ -108,17 +268,112 @@ mod tests {
 
     #[test]
     fn exec_works() {
-        let stats = exec("true", [""; 0]).unwrap();
+        let stats = Options::default().exec("true", [""; 0]).unwrap();
         assert_ne!(stats.pid(), Pid::from_raw(0));
         assert_ne!(stats.pid(), Pid::from_raw(-1));
         assert_eq!(stats.exit_code(), 0);
 
-        let error = exec("false", [""; 0]).unwrap_err();
+        let error = Options::default().exec("false", [""; 0]).unwrap_err();
         assert_eq!(error.to_string(), "false failed (status = 1)");
 
-        let stats = exec("echo", ["hi"]).unwrap();
+        let stats = Options::default().exec("echo", ["hi"]).unwrap();
         assert_ne!(stats.pid(), Pid::from_raw(0));
         assert_ne!(stats.pid(), Pid::from_raw(-1));
         assert_eq!(stats.exit_code(), 0);
+        assert_eq!(stats.cgroup(), None);
+    }
+
+    #[test]
+    fn exec_with_stdio() {
+        let dir = tempfile::tempdir().unwrap();
+        let (input, output) = (dir.path().join("input"), dir.path().join("output"));
+        std::fs::write(&input, "roundtrip\n").unwrap();
+
+        let stats = Options::default()
+            .exec_with_stdio("cat", [""; 0], File::open(&input).unwrap(), File::create(&output).unwrap())
+            .unwrap();
+        assert_eq!(stats.exit_code(), 0);
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "roundtrip\n");
+    }
+
+    #[test]
+    #[ignore = "needs root and a writable cgroup2 filesystem"]
+    fn exec_in_cgroup() {
+        let options = Options {
+            cgroup: true,
+            ..Options::default()
+        };
+        let script = "i=0; while [ $i -lt 20000 ]; do i=$((i+1)); done";
+        let stats = options
+            .exec("/bin/sh", ["-c", &format!("/bin/sh -c '{script}' & wait")])
+            .unwrap();
+        assert_eq!(stats.exit_code(), 0);
+
+        let cgroup = stats.cgroup().unwrap();
+        assert!(cgroup.cpu.usage > Duration::ZERO, "grandchild CPU time is accounted");
+        assert!(cgroup.cpu.usage >= cgroup.cpu.user, "user time is part of usage");
+        release_cgroups();
+    }
+
+    #[test]
+    fn exec_timeout() {
+        let options = Options {
+            timeout: Some(Duration::from_millis(100)),
+            ..Options::default()
+        };
+        let start_time = Instant::now();
+        let error = options
+            .exec("/bin/sh", ["-c", "sleep 10 & sleep 10; wait"])
+            .unwrap_err();
+        assert!(start_time.elapsed() < Duration::from_secs(5), "whole process group was killed");
+
+        let error = error.downcast::<TimeoutError>().unwrap();
+        assert_eq!(error.to_string(), "/bin/sh timed out after 100ms");
+        assert!(error.stats.real_time() >= error.timeout, "measured until killed");
+
+        let stats = options.exec("true", [""; 0]).unwrap();
+        assert_eq!(stats.exit_code(), 0);
+    }
+
+    #[test]
+    fn exec_signaled() {
+        let error = Options::default().exec("/bin/sh", ["-c", "kill -TERM $$"]).unwrap_err();
+        let error = error.downcast::<SignalError>().unwrap();
+        assert_eq!(error.to_string(), "/bin/sh killed by SIGTERM");
+        assert_ne!(error.stats.real_time(), Duration::ZERO);
+
+        let error = Options::default()
+            .exec("/bin/sh", ["-c", "echo out of memory >&2; kill -KILL $$"])
+            .unwrap_err();
+        let error = error.downcast::<SignalError>().unwrap();
+        assert_eq!(error.to_string(), "/bin/sh killed by SIGKILL: out of memory");
+        assert_ne!(error.stats.real_time(), Duration::ZERO);
+    }
+
+    #[test]
+    fn exec_sampled() {
+        let options = Options {
+            sample_interval: Some(Duration::from_millis(10)),
+            ..Options::default()
+        };
+        let stats = options.exec("sleep", ["0.2"]).unwrap();
+
+        let timeline = stats.timeline().unwrap();
+        assert_eq!(timeline.interval, Duration::from_millis(10));
+        assert!(timeline.samples.len() >= 5, "sampled while running");
+        assert!(timeline.samples.is_sorted_by_key(|sample| sample.elapsed), "samples are in order");
+        assert!(timeline.peak_rss().unwrap() <= stats.max_rss(), "rusage keeps the maximum");
+    }
+
+    #[test]
+    fn exec_counts_io() {
+        let stats = Options::default()
+            .exec("/bin/sh", ["-c", "head -c 100000 /dev/zero > /dev/null"])
+            .unwrap();
+
+        let io = stats.io().unwrap();
+        assert!(io.rchar >= 100_000, "includes reaped children");
+        assert!(io.wchar >= 100_000, "includes reaped children");
+        assert!(io.syscr > 0, "counts syscalls");
     }
 }
@@ -0,0 +1,103 @@
+//! Concurrent capture of command output.
+
+use std::io::{self, BufRead, BufReader, Read};
+use std::process::Child;
+use std::thread;
+
+use anyhow::Result;
+use log::Level;
+
+use crate::utils::strings::{self, utf8_lossy};
+
+/// Longest line read at once, longer lines are split.
+const MAX_LINE_LENGTH: u64 = 64 * 1024;
+
+/// Drain stdout and stderr from child at the same time, logging lines as they arrive.
+///
+/// Only the last `limit` bytes of each stream are kept.
+pub fn capture(process: &mut Child, name: &str, limit: usize) -> Result<(Vec<u8>, Vec<u8>)> {
+    drop(process.stdin.take());
+    let (stdout, stderr) = (process.stdout.take(), process.stderr.take());
+
+    thread::scope(|scope| {
+        let stderr = thread::Builder::new()
+            .name(format!("{name}-stderr"))
+            .spawn_scoped(scope, || stderr.map(|pipe| drain(pipe, name, Level::Warn, limit)).transpose())?;
+        let stdout = stdout.map(|pipe| drain(pipe, name, Level::Debug, limit)).transpose()?;
+        log::trace!("capture: stdout");
+
+        let stderr = stderr.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic))?;
+        log::trace!("capture: stderr");
+        Ok((stdout.unwrap_or_default(), stderr.unwrap_or_default()))
+    })
+}
+
+/// Read a pipe until EOF, logging each line at `level`.
+///
+/// Older output is discarded when more than `limit` bytes were read.
+fn drain(pipe: impl Read, name: &str, level: Level, limit: usize) -> io::Result<Vec<u8>> {
+    let mut reader = BufReader::new(pipe);
+    let (mut kept, mut line) = (Vec::new(), Vec::new());
+    let mut discarded = 0_usize;
+
+    loop {
+        line.clear();
+        if reader.by_ref().take(MAX_LINE_LENGTH).read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+        for text in strings::lines(&line) {
+            log::log!(level, "{name}: {}", utf8_lossy(text));
+        }
+
+        kept.extend_from_slice(&line);
+        // amortized, so output is not shifted for every line
+        if kept.len() > limit.saturating_mul(2) {
+            let excess = kept.len() - limit;
+            kept.drain(..excess);
+            discarded += excess;
+        }
+    }
+
+    if kept.len() > limit {
+        let excess = kept.len() - limit;
+        kept.drain(..excess);
+        discarded += excess;
+    }
+    if discarded > 0 {
+        log::debug!("{name}: discarded {discarded} bytes of {level} output above the limit");
+    }
+    Ok(kept)
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use test_log::test;
+
+    use super::*;
+    use crate::utils::command;
+
+    #[test]
+    fn keeps_last_bytes() {
+        let input: Vec<_> = (1..=1000)
+            .flat_map(|idx| format!("line {idx}\n").into_bytes())
+            .collect();
+        let kept = drain(input.as_slice(), "test", Level::Trace, 20).unwrap();
+        assert_eq!(kept, b"\nline 999\nline 1000\n");
+
+        let kept = drain(b"short\n".as_slice(), "test", Level::Trace, 20).unwrap();
+        assert_eq!(kept, b"short\n");
+    }
+
+    #[test]
+    fn drains_both_pipes() {
+        // fills the stderr pipe before writing to stdout
+        let script = "head -c 200000 /dev/zero | tr '\\0' x >&2; echo >&2; echo done";
+        let mut process = command::command("/bin/sh", ["-c", script]).spawn().unwrap();
+        let (stdout, stderr) = capture(&mut process, "sh", 1024).unwrap();
+        assert!(process.wait().unwrap().success(), "process finished");
+
+        assert_eq!(stdout, b"done\n");
+        assert_eq!(stderr.len(), 1024);
+    }
+}
@@ -0,0 +1,209 @@
+//! Process information from `/proc/<pid>`.
+//!
+//! See [proc_pid_stat(5)](https://man.archlinux.org/man/proc_pid_stat.5),
+//! [proc_pid_status(5)](https://man.archlinux.org/man/proc_pid_status.5),
+//! [proc_pid_io(5)](https://man.archlinux.org/man/proc_pid_io.5) and
+//! [proc_pid_task(5)](https://man.archlinux.org/man/proc_pid_task.5).
+
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use byte_unit::{Byte, Unit};
+use nix::unistd::Pid;
+
+/// Read a file from `/proc/<pid>`.
+fn read(pid: Pid, name: &str) -> Result<String> {
+    let path = format!("/proc/{pid}/{name}");
+    std::fs::read_to_string(&path).with_context(|| format!("could not read {path}"))
+}
+
+/// CPU time and threads, from `/proc/<pid>/stat`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ProcStat {
+    /// User plus system CPU time of the process, without children.
+    pub cpu_time: Duration,
+    /// User plus system CPU time of the children it waited for.
+    pub children_cpu_time: Duration,
+    /// Number of threads.
+    pub threads: u64,
+}
+
+impl ProcStat {
+    /// Read current values for a running process.
+    pub fn read(pid: Pid) -> Result<Self> {
+        let stat = read(pid, "stat")?;
+        Self::parse(&stat, clock_ticks()).with_context(|| format!("invalid /proc/{pid}/stat: {stat:?}"))
+    }
+
+    /// Parse the contents of `/proc/<pid>/stat`, using `ticks` per second.
+    fn parse(stat: &str, ticks: u64) -> Option<Self> {
+        // command name may contain spaces and parenthesis
+        let (_, fields) = stat.rsplit_once(')')?;
+        let fields: Vec<_> = fields.split_whitespace().collect();
+        // numbered from the field after the command name, which is the third
+        let field = |number: usize| fields.get(number - 3)?.parse::<u64>().ok();
+
+        let time = |ticks_field: u64| Duration::from_secs(ticks_field).checked_div(u32::try_from(ticks).ok()?);
+        Some(Self {
+            cpu_time: time(field(14)?.saturating_add(field(15)?))?,
+            children_cpu_time: time(field(16)?.saturating_add(field(17)?))?,
+            threads: field(20)?,
+        })
+    }
+}
+
+/// Running children of all threads of a process, from `/proc/<pid>/task/<tid>/children`.
+///
+/// Empty if the process exited or the kernel was built without `CONFIG_PROC_CHILDREN`.
+pub fn children(pid: Pid) -> Vec<Pid> {
+    let Ok(tasks) = std::fs::read_dir(format!("/proc/{pid}/task")) else {
+        return Vec::new();
+    };
+    tasks
+        .filter_map(|task| std::fs::read_to_string(task.ok()?.path().join("children")).ok())
+        .flat_map(|children| parse_children(&children))
+        .collect()
+}
+
+/// Parse the contents of `/proc/<pid>/task/<tid>/children`.
+fn parse_children(children: &str) -> Vec<Pid> {
+    children
+        .split_whitespace()
+        .filter_map(|pid| pid.parse().ok().map(Pid::from_raw))
+        .collect()
+}
+
+/// Resident memory, from `/proc/<pid>/status`.
+///
+/// Zero after the process exited.
+pub fn resident_memory(pid: Pid) -> Result<Byte> {
+    Ok(parse_rss(&read(pid, "status")?).unwrap_or(Byte::MIN))
+}
+
+/// Parse `VmRSS` from the contents of `/proc/<pid>/status`.
+fn parse_rss(status: &str) -> Option<Byte> {
+    let line = status.lines().find_map(|line| line.strip_prefix("VmRSS:"))?;
+    let kibibytes = line.trim().strip_suffix("kB")?.trim().parse().ok()?;
+    Byte::from_u64_with_unit(kibibytes, Unit::KiB)
+}
+
+/// I/O counters, from `/proc/<pid>/io`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ProcIo {
+    /// Bytes read by syscalls, including the page cache.
+    pub rchar: u64,
+    /// Bytes written by syscalls, including the page cache.
+    pub wchar: u64,
+    /// Read syscalls.
+    pub syscr: u64,
+    /// Write syscalls.
+    pub syscw: u64,
+    /// Bytes fetched from storage.
+    pub read_bytes: u64,
+    /// Bytes sent to storage.
+    pub write_bytes: u64,
+    /// Bytes written to the page cache, but truncated before reaching storage.
+    pub cancelled_write_bytes: u64,
+}
+
+impl ProcIo {
+    /// Read current values for a process, requires `ptrace` access.
+    pub fn read(pid: Pid) -> Result<Self> {
+        Ok(Self::parse(&read(pid, "io")?))
+    }
+
+    /// Counters of two processes together.
+    #[must_use]
+    pub const fn saturating_add(self, other: Self) -> Self {
+        Self {
+            rchar: self.rchar.saturating_add(other.rchar),
+            wchar: self.wchar.saturating_add(other.wchar),
+            syscr: self.syscr.saturating_add(other.syscr),
+            syscw: self.syscw.saturating_add(other.syscw),
+            read_bytes: self.read_bytes.saturating_add(other.read_bytes),
+            write_bytes: self.write_bytes.saturating_add(other.write_bytes),
+            cancelled_write_bytes: self.cancelled_write_bytes.saturating_add(other.cancelled_write_bytes),
+        }
+    }
+
+    /// Parse the contents of `/proc/<pid>/io`.
+    fn parse(io: &str) -> Self {
+        let mut counters = Self::default();
+        for (key, value) in io.lines().filter_map(|line| line.split_once(':')) {
+            let Ok(value) = value.trim().parse() else {
+                continue;
+            };
+            match key {
+                "rchar" => counters.rchar = value,
+                "wchar" => counters.wchar = value,
+                "syscr" => counters.syscr = value,
+                "syscw" => counters.syscw = value,
+                "read_bytes" => counters.read_bytes = value,
+                "write_bytes" => counters.write_bytes = value,
+                "cancelled_write_bytes" => counters.cancelled_write_bytes = value,
+                _ => (),
+            }
+        }
+        counters
+    }
+}
+
+/// Clock ticks per second, used in `/proc/<pid>/stat`.
+fn clock_ticks() -> u64 {
+    // SAFETY: sysconf has no preconditions
+    let ticks = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+    u64::try_from(ticks).unwrap_or(100)
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use test_log::test;
+
+    use super::*;
+
+    #[test]
+    fn parses_stat() {
+        let stat = "1234 (zstd (mt) x) R 1 1234 1234 0 -1 4194560 500 0 0 0 250 50 0 0 20 0 4 0 100 0 0";
+        let stat = ProcStat::parse(stat, 100).unwrap();
+        assert_eq!(stat.cpu_time, Duration::from_secs(3));
+        assert_eq!(stat.children_cpu_time, Duration::ZERO);
+        assert_eq!(stat.threads, 4);
+        let waited = "1234 (sh) S 1 1234 1234 0 -1 4194560 500 0 0 0 10 10 100 50 20 0 1 0 100 0 0";
+        assert_eq!(ProcStat::parse(waited, 100).unwrap().children_cpu_time, Duration::from_millis(1500));
+
+        assert_eq!(ProcStat::parse("1234 (zstd) R 1", 100), None);
+    }
+
+    #[test]
+    fn parses_status_and_io() {
+        let status = "Name:\tzstd\nVmPeak:\t  20000 kB\nVmRSS:\t   1024 kB\nThreads:\t4\n";
+        assert_eq!(parse_rss(status), Some(Byte::MEBIBYTE));
+        assert_eq!(parse_rss("Name:\tzombie\n"), None);
+
+        let io = ProcIo::parse(
+            "rchar: 100\nwchar: 200\nsyscr: 3\nsyscw: 4\nread_bytes: 4096\nwrite_bytes: 8192\n\
+             cancelled_write_bytes: 0\n",
+        );
+        assert_eq!((io.rchar, io.wchar, io.syscr, io.syscw), (100, 200, 3, 4));
+        assert_eq!((io.read_bytes, io.write_bytes), (4096, 8192));
+        assert_eq!(io.saturating_add(io).rchar, 200);
+
+        assert_eq!(parse_children("42 43 \n"), [Pid::from_raw(42), Pid::from_raw(43)]);
+        assert_eq!(parse_children(""), []);
+    }
+
+    #[test]
+    fn reads_current_process() {
+        let pid = Pid::this();
+        assert!(ProcStat::read(pid).unwrap().threads >= 1, "at least the main thread");
+        assert!(resident_memory(pid).unwrap() > Byte::MIN, "process is running");
+        assert!(ProcIo::read(pid).unwrap().rchar > 0, "read its own files");
+
+        let mut child = std::process::Command::new("sleep").arg("10").spawn().unwrap();
+        let found = children(pid).contains(&Pid::from_raw(child.id().try_into().unwrap()));
+        child.kill().unwrap();
+        child.wait().unwrap();
+        assert!(found, "running child");
+    }
+}
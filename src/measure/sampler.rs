@@ -0,0 +1,221 @@
+//! Periodic sampling of a running process.
+
+use std::io::{self, Write};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use byte_unit::Byte;
+use nix::unistd::Pid;
+
+use super::procfs::{self, ProcIo, ProcStat};
+
+/// Resource usage of a process and its descendants at some point of its execution.
+///
+/// Exited descendants only count once waited for by their parent, like in `getrusage(2)`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sample {
+    /// Time since the process started.
+    pub elapsed: Duration,
+    /// Resident memory of the running processes, counting shared pages in each of them.
+    pub rss: Byte,
+    /// User plus system CPU time so far.
+    pub cpu_time: Duration,
+    /// Number of threads of the running processes.
+    pub threads: u64,
+    /// I/O counters so far, if readable.
+    pub io: Option<ProcIo>,
+}
+
+impl Sample {
+    /// Read current values from `/proc/<pid>`, adding those of its running descendants.
+    fn read(pid: Pid, elapsed: Duration) -> Result<Self> {
+        let stat = ProcStat::read(pid)?;
+        let mut sample = Self {
+            elapsed,
+            rss: procfs::resident_memory(pid)?,
+            cpu_time: stat.cpu_time.saturating_add(stat.children_cpu_time),
+            threads: stat.threads,
+            io: ProcIo::read(pid).ok(),
+        };
+
+        let mut pending = procfs::children(pid);
+        while let Some(child) = pending.pop() {
+            // may have exited since listed
+            let Ok(stat) = ProcStat::read(child) else {
+                continue;
+            };
+            sample.cpu_time = sample
+                .cpu_time
+                .saturating_add(stat.cpu_time.saturating_add(stat.children_cpu_time));
+            sample.threads = sample.threads.saturating_add(stat.threads);
+            if let Ok(rss) = procfs::resident_memory(child) {
+                sample.rss = sample.rss.add(rss).unwrap_or(Byte::MAX);
+            }
+            sample.io = sample
+                .io
+                .zip(ProcIo::read(child).ok())
+                .map(|(io, child)| io.saturating_add(child));
+            pending.extend(procfs::children(child));
+        }
+        Ok(sample)
+    }
+}
+
+/// Samples taken at a fixed interval during a run.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Timeline {
+    /// Time between samples.
+    pub interval: Duration,
+    /// Samples, in order.
+    pub samples: Vec<Sample>,
+}
+
+impl Timeline {
+    /// Highest resident memory sampled.
+    #[must_use]
+    pub fn peak_rss(&self) -> Option<Byte> {
+        self.samples.iter().map(|sample| sample.rss).max()
+    }
+
+    /// Write samples as CSV, with a header line.
+    ///
+    /// Times are in microseconds and sizes in bytes. I/O columns are empty when unreadable.
+    ///
+    /// # Errors
+    ///
+    /// Failed to write.
+    pub fn write_csv(&self, mut writer: impl Write) -> io::Result<()> {
+        writeln!(writer, "elapsed_us,rss_bytes,cpu_time_us,threads,read_bytes,write_bytes,rchar,wchar,syscr,syscw")?;
+        for sample in &self.samples {
+            write!(
+                writer,
+                "{},{},{},{},",
+                sample.elapsed.as_micros(),
+                sample.rss.as_u64(),
+                sample.cpu_time.as_micros(),
+                sample.threads
+            )?;
+            match &sample.io {
+                Some(io) => writeln!(
+                    writer,
+                    "{},{},{},{},{},{}",
+                    io.read_bytes, io.write_bytes, io.rchar, io.wchar, io.syscr, io.syscw
+                )?,
+                None => writeln!(writer, ",,,,,")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Background thread sampling a process until stopped.
+#[derive(Debug)]
+pub struct Sampler {
+    /// Closed when the process finishes.
+    cancel: Sender<()>,
+    /// Returns the samples taken.
+    thread: JoinHandle<Vec<Sample>>,
+    /// Time between samples.
+    interval: Duration,
+    /// Process being sampled.
+    pid: Pid,
+    /// When the process started.
+    start_time: Instant,
+}
+
+impl Sampler {
+    /// Start sampling `pid` every `interval`, with times relative to `start_time`.
+    ///
+    /// # Errors
+    ///
+    /// Could not spawn the sampler thread.
+    pub fn start(pid: Pid, interval: Duration, start_time: Instant) -> Result<Self> {
+        let (cancel, cancelled) = mpsc::channel::<()>();
+        let thread = thread::Builder::new().name(format!("sampler-{pid}")).spawn(move || {
+            let mut samples = Vec::new();
+            loop {
+                match Sample::read(pid, start_time.elapsed()) {
+                    Ok(sample) => samples.push(sample),
+                    Err(error) => log::trace!("sampler: pid={pid}, error={error:#}"),
+                }
+                match cancelled.recv_timeout(interval) {
+                    Err(RecvTimeoutError::Timeout) => (),
+                    Ok(()) | Err(RecvTimeoutError::Disconnected) => return samples,
+                }
+            }
+        })?;
+        Ok(Self {
+            cancel,
+            thread,
+            interval,
+            pid,
+            start_time,
+        })
+    }
+
+    /// Stop sampling and collect the samples.
+    ///
+    /// If the process exited but was not reaped yet, a last sample has its final CPU time and I/O counters.
+    pub fn stop(self) -> Timeline {
+        drop(self.cancel);
+        let mut samples = self.thread.join().unwrap_or_else(|_| {
+            log::warn!("sampler: thread panicked");
+            Vec::new()
+        });
+        match Sample::read(self.pid, self.start_time.elapsed()) {
+            Ok(sample) => samples.push(sample),
+            Err(error) => log::trace!("sampler: pid={}, error={error:#}", self.pid),
+        }
+        log::debug!("sampler: {} samples", samples.len());
+        Timeline {
+            interval: self.interval,
+            samples,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use test_log::test;
+
+    use super::*;
+
+    #[test]
+    fn writes_csv() {
+        let sample = Sample {
+            elapsed: Duration::from_millis(10),
+            rss: Byte::MEBIBYTE,
+            cpu_time: Duration::from_millis(5),
+            threads: 2,
+            io: None,
+        };
+        let timeline = Timeline {
+            interval: Duration::from_millis(10),
+            samples: vec![
+                sample,
+                Sample {
+                    elapsed: Duration::from_millis(20),
+                    io: Some(ProcIo {
+                        read_bytes: 4096,
+                        rchar: 100,
+                        ..ProcIo::default()
+                    }),
+                    ..sample
+                },
+            ],
+        };
+
+        let mut csv = Vec::new();
+        timeline.write_csv(&mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "elapsed_us,rss_bytes,cpu_time_us,threads,read_bytes,write_bytes,rchar,wchar,syscr,syscw\n\
+             10000,1048576,5000,2,,,,,,\n\
+             20000,1048576,5000,2,4096,0,100,0,0,0\n"
+        );
+        assert_eq!(timeline.peak_rss(), Some(Byte::MEBIBYTE));
+    }
+}
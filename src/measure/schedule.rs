@@ -0,0 +1,335 @@
+//! CPU affinity, scheduling and I/O priority for measured commands.
+
+use std::fmt;
+use std::io;
+use std::num::NonZeroU32;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::str::FromStr;
+
+use anyhow::{Context, Result, bail};
+use clap::ValueEnum;
+
+/// Shift for the class in an I/O priority value, see [ioprio_set(2)](https://man.archlinux.org/man/ioprio_set.2).
+const IOPRIO_CLASS_SHIFT: u32 = 13;
+/// Target of `ioprio_set` is a process.
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+
+/// Set of CPUs, in the `cpuset` list format like `0-3,6`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CpuList(Vec<usize>);
+
+impl CpuList {
+    /// Number of CPUs in the list.
+    #[must_use]
+    pub fn count(&self) -> NonZeroU32 {
+        u32::try_from(self.0.len())
+            .ok()
+            .and_then(NonZeroU32::new)
+            .unwrap_or(NonZeroU32::MIN)
+    }
+
+    /// Build the mask for `sched_setaffinity`.
+    fn cpu_set(&self) -> Result<libc::cpu_set_t> {
+        // SAFETY: libc structs have valid all-zero byte-patterns
+        let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+        let size = usize::try_from(libc::CPU_SETSIZE).unwrap_or(0);
+        for &cpu in &self.0 {
+            if cpu >= size {
+                bail!("CPU {cpu} is above the maximum of {}", libc::CPU_SETSIZE - 1);
+            }
+            // SAFETY: the CPU is inside the set, checked above
+            unsafe { libc::CPU_SET(cpu, &mut set) };
+        }
+        Ok(set)
+    }
+}
+
+impl FromStr for CpuList {
+    type Err = anyhow::Error;
+
+    fn from_str(list: &str) -> Result<Self> {
+        let mut cpus = Vec::new();
+        for range in list.split(',').map(str::trim) {
+            let (start, end) = range.split_once('-').unwrap_or((range, range));
+            let parse = |cpu: &str| {
+                cpu.trim()
+                    .parse::<usize>()
+                    .with_context(|| format!("invalid CPU list: {list:?}"))
+            };
+            let (low, high) = (parse(start)?, parse(end)?);
+            if low > high {
+                bail!("invalid CPU range: {range:?}");
+            }
+            cpus.extend(low..=high);
+        }
+        cpus.sort_unstable();
+        cpus.dedup();
+        Ok(Self(cpus))
+    }
+}
+
+impl fmt::Display for CpuList {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut cpus = self.0.iter().copied().peekable();
+        let mut first = true;
+        while let Some(start) = cpus.next() {
+            let mut end = start;
+            while let Some(next) = cpus.next_if(|&next| next == end + 1) {
+                end = next;
+            }
+            if !first {
+                f.write_str(",")?;
+            }
+            first = false;
+            if start == end {
+                write!(f, "{start}")?;
+            } else {
+                write!(f, "{start}-{end}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Scheduling policy, see [sched(7)](https://man.archlinux.org/man/sched.7).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, ValueEnum)]
+pub enum Policy {
+    /// Default time-sharing policy.
+    Other,
+    /// Time-sharing for CPU-bound work, with fewer preemptions.
+    Batch,
+    /// Real-time, at the lowest priority. Still runs before every time-sharing process.
+    Fifo,
+}
+
+impl Policy {
+    /// Value for `sched_setscheduler`.
+    const fn raw(self) -> libc::c_int {
+        match self {
+            Self::Other => libc::SCHED_OTHER,
+            Self::Batch => libc::SCHED_BATCH,
+            Self::Fifo => libc::SCHED_FIFO,
+        }
+    }
+
+    /// Static priority, which is only non-zero for real-time policies.
+    const fn priority(self) -> libc::c_int {
+        match self {
+            Self::Other | Self::Batch => 0,
+            Self::Fifo => 1,
+        }
+    }
+}
+
+impl fmt::Display for Policy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Other => "other",
+            Self::Batch => "batch",
+            Self::Fifo => "fifo",
+        })
+    }
+}
+
+/// I/O scheduling class and level, in the format `CLASS[:LEVEL]`.
+///
+/// Levels go from 0 (highest) to 7 and are ignored for the `idle` class.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum IoPriority {
+    /// Served before other classes.
+    Realtime(u8),
+    /// Default class.
+    BestEffort(u8),
+    /// Only served when the disk is idle.
+    Idle,
+}
+
+impl IoPriority {
+    /// Value for `ioprio_set`.
+    fn raw(self) -> libc::c_int {
+        let (class, level) = match self {
+            Self::Realtime(level) => (1, level),
+            Self::BestEffort(level) => (2, level),
+            Self::Idle => (3, 0),
+        };
+        (class << IOPRIO_CLASS_SHIFT) | libc::c_int::from(level)
+    }
+}
+
+impl FromStr for IoPriority {
+    type Err = anyhow::Error;
+
+    fn from_str(spec: &str) -> Result<Self> {
+        let (class, level) = spec.split_once(':').unwrap_or((spec, "4"));
+        let Ok(level @ 0..=7) = level.parse::<u8>() else {
+            bail!("expected I/O priority level between 0 and 7: {spec:?}");
+        };
+        match class {
+            "realtime" => Ok(Self::Realtime(level)),
+            "best-effort" => Ok(Self::BestEffort(level)),
+            "idle" => Ok(Self::Idle),
+            _ => bail!("expected realtime, best-effort or idle I/O class: {spec:?}"),
+        }
+    }
+}
+
+impl fmt::Display for IoPriority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Realtime(level) => write!(f, "realtime:{level}"),
+            Self::BestEffort(level) => write!(f, "best-effort:{level}"),
+            Self::Idle => f.write_str("idle"),
+        }
+    }
+}
+
+/// Where and how measured commands are scheduled.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Schedule {
+    /// CPU affinity.
+    pub cpus: Option<CpuList>,
+    /// Nice level, from -20 (highest priority) to 19.
+    pub nice: Option<i32>,
+    /// Scheduling policy.
+    pub policy: Option<Policy>,
+    /// I/O scheduling class and level.
+    pub ioprio: Option<IoPriority>,
+}
+
+impl Schedule {
+    /// Apply the settings in the child, before `exec`. Descendants inherit them.
+    ///
+    /// # Errors
+    ///
+    /// Invalid CPU list.
+    pub fn apply(&self, command: &mut Command) -> Result<()> {
+        if *self == Self::default() {
+            return Ok(());
+        }
+
+        let cpu_set = self.cpus.as_ref().map(CpuList::cpu_set).transpose()?;
+        let nice = self.nice;
+        let policy = self.policy.map(|policy| (policy.raw(), policy.priority()));
+        let ioprio = self.ioprio.map(IoPriority::raw);
+
+        let setup = move || {
+            if let Some(cpu_set) = &cpu_set {
+                // SAFETY: the mask is valid and has the given size
+                let result = unsafe { libc::sched_setaffinity(0, size_of::<libc::cpu_set_t>(), cpu_set) };
+                check(result)?;
+            }
+            if let Some(nice) = nice {
+                // SAFETY: plain syscall, with no pointers
+                let result = unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) };
+                check(result)?;
+            }
+            if let Some((policy, priority)) = policy {
+                let param = libc::sched_param {
+                    sched_priority: priority,
+                };
+                // SAFETY: the parameter is valid for the call
+                let result = unsafe { libc::sched_setscheduler(0, policy, &raw const param) };
+                check(result)?;
+            }
+            if let Some(ioprio) = ioprio {
+                // SAFETY: plain syscall, with no pointers
+                let result = unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ioprio) };
+                check(result)?;
+            }
+            Ok(())
+        };
+        // SAFETY: the closure only calls async-signal-safe syscalls, without allocating
+        unsafe { command.pre_exec(setup) };
+        Ok(())
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if *self == Self::default() {
+            return f.write_str("default");
+        }
+
+        let mut separator = "";
+        if let Some(cpus) = &self.cpus {
+            write!(f, "{separator}cpus={cpus}")?;
+            separator = ", ";
+        }
+        if let Some(nice) = self.nice {
+            write!(f, "{separator}nice={nice}")?;
+            separator = ", ";
+        }
+        if let Some(policy) = self.policy {
+            write!(f, "{separator}policy={policy}")?;
+            separator = ", ";
+        }
+        if let Some(ioprio) = self.ioprio {
+            write!(f, "{separator}ioprio={ioprio}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Convert a syscall result to an error, using `errno`.
+fn check(result: impl Into<i64>) -> io::Result<()> {
+    if result.into() == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use test_log::test;
+
+    use super::*;
+    use crate::utils::command;
+
+    #[test]
+    fn parses_cpu_list() {
+        let cpus: CpuList = "6, 0-3,2".parse().unwrap();
+        assert_eq!(cpus, CpuList(vec![0, 1, 2, 3, 6]));
+        assert_eq!(cpus.to_string(), "0-3,6");
+
+        assert_eq!("3-1".parse::<CpuList>().unwrap_err().to_string(), "invalid CPU range: \"3-1\"");
+        assert_eq!("a".parse::<CpuList>().unwrap_err().to_string(), "invalid CPU list: \"a\"");
+    }
+
+    #[test]
+    fn parses_ioprio() {
+        assert_eq!("best-effort".parse::<IoPriority>().unwrap(), IoPriority::BestEffort(4));
+        assert_eq!("realtime:0".parse::<IoPriority>().unwrap(), IoPriority::Realtime(0));
+        assert_eq!("idle".parse::<IoPriority>().unwrap().to_string(), "idle");
+        assert_eq!(IoPriority::BestEffort(7).raw(), 0x4007);
+        assert!("best-effort:8".parse::<IoPriority>().is_err(), "level out of range");
+        assert!("fast".parse::<IoPriority>().is_err(), "unknown class");
+    }
+
+    #[test]
+    fn applies_before_exec() {
+        let schedule = Schedule {
+            cpus: Some("0".parse().unwrap()),
+            nice: Some(5),
+            policy: Some(Policy::Batch),
+            ioprio: Some(IoPriority::Idle),
+        };
+        assert_eq!(schedule.to_string(), "cpus=0, nice=5, policy=batch, ioprio=idle");
+        assert_eq!(Schedule::default().to_string(), "default");
+
+        let mut cat = command::command("cat", ["/proc/self/status", "/proc/self/stat"]);
+        schedule.apply(&mut cat).unwrap();
+        let output = cat.output().unwrap();
+        assert!(output.status.success(), "applied settings");
+
+        let output = String::from_utf8(output.stdout).unwrap();
+        assert!(output.contains("Cpus_allowed_list:\t0\n"), "{output}");
+        let stat = output.lines().last().unwrap();
+        let (_, fields) = stat.rsplit_once(')').unwrap();
+        let fields: Vec<_> = fields.split_whitespace().collect();
+        assert_eq!(fields[19 - 3], "5", "nice");
+        assert_eq!(fields[41 - 3], libc::SCHED_BATCH.to_string(), "policy");
+    }
+}
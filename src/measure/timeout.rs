@@ -0,0 +1,76 @@
+//! Time limits for measured commands.
+
+use std::error::Error;
+use std::fmt;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use anyhow::Result;
+use nix::sys::signal::{Signal, killpg};
+use nix::unistd::Pid;
+
+use super::Stats;
+
+/// A command killed for running longer than its time limit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimeoutError {
+    /// Name of the command.
+    pub name: String,
+    /// Time limit that expired.
+    pub timeout: Duration,
+    /// Resource usage until the command was killed.
+    pub stats: Stats,
+}
+
+impl fmt::Display for TimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} timed out after {:?}", self.name, self.timeout)
+    }
+}
+
+impl Error for TimeoutError {}
+
+/// Background thread that kills a process group when its time limit expires.
+#[derive(Debug)]
+pub struct Watchdog {
+    /// Closed when the process finishes in time.
+    cancel: Sender<()>,
+    /// Returns whether the process group was killed.
+    thread: JoinHandle<bool>,
+}
+
+impl Watchdog {
+    /// Start watching the process group led by `pgid`.
+    ///
+    /// Must be stopped before the leader is reaped, so the process group can't be reused by then.
+    ///
+    /// # Errors
+    ///
+    /// Could not spawn the watcher thread.
+    pub fn start(pgid: Pid, timeout: Duration) -> Result<Self> {
+        let (cancel, cancelled) = mpsc::channel::<()>();
+        let thread = thread::Builder::new().name(format!("watchdog-{pgid}")).spawn(move || {
+            match cancelled.recv_timeout(timeout) {
+                Err(RecvTimeoutError::Timeout) => {
+                    log::warn!("watchdog: pgid={pgid}, timed out after {timeout:?}, killing process group");
+                    if let Err(errno) = killpg(pgid, Signal::SIGKILL) {
+                        log::warn!("watchdog: pgid={pgid}, could not kill process group: {errno}");
+                    }
+                    true
+                }
+                Ok(()) | Err(RecvTimeoutError::Disconnected) => false,
+            }
+        })?;
+        Ok(Self { cancel, thread })
+    }
+
+    /// Stop watching, returning whether the time limit expired before.
+    pub fn stop(self) -> bool {
+        drop(self.cancel);
+        self.thread.join().unwrap_or_else(|_| {
+            log::warn!("watchdog: thread panicked");
+            false
+        })
+    }
+}
@@ -1,14 +1,19 @@
 //! Access and display resource usage.
 
+use std::fmt;
+use std::num::NonZeroU32;
 use std::os::unix::process::ExitStatusExt;
 use std::process::ExitStatus;
 use std::time::Duration;
 
 use anyhow::{Result, bail};
 use byte_unit::{Byte, Unit};
+use nix::sys::signal::Signal;
 use nix::sys::wait::WaitStatus;
 use nix::unistd::Pid;
 
+use super::{CgroupStats, ProcIo, Timeline};
+
 /// Convert from `libc`'s [`timeval`](libc::timeval) to `chrono`'s [`Duration`].
 #[must_use]
 const fn duration(timeval: libc::timeval) -> Duration {
@@ -38,11 +43,41 @@ const fn count(value: i64) -> u64 {
     value as u64
 }
 
+/// How a measured process terminated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Termination {
+    /// Process exited normally, with an exit code.
+    Exited(i32),
+    /// Process was killed by a signal.
+    Signaled {
+        /// Signal that terminated the process.
+        signal: Signal,
+        /// A core dump was produced.
+        core_dumped: bool,
+    },
+}
+
+impl fmt::Display for Termination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Exited(code) => write!(f, "exited with status {code}"),
+            Self::Signaled {
+                signal,
+                core_dumped: false,
+            } => write!(f, "killed by {signal}"),
+            Self::Signaled {
+                signal,
+                core_dumped: true,
+            } => write!(f, "killed by {signal} (core dumped)"),
+        }
+    }
+}
+
 /// Resource usage statistics for a finished process.
 ///
 /// See [getrusage(2)](https://man.archlinux.org/man/getrusage.2.en) and
 /// [Resource Usage](https://www.gnu.org/software/libc/manual/html_node/Resource-Usage.html).
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Stats {
     /// Resource usage stats.
     usage: libc::rusage,
@@ -50,21 +85,26 @@ pub struct Stats {
     wait_status: WaitStatus,
     /// Child exit status.
     exit_status: ExitStatus,
-    /// Real (wall) time.
-    wall_time: Duration,
-    /// Virtual (CPU) time.
-    monotonic_time: Duration,
+    /// Exit code or terminating signal.
+    termination: Termination,
+    /// Real (wall) time, from a monotonic clock.
+    real_time: Duration,
+    /// Usage of the whole process tree, if measured in a cgroup.
+    cgroup: Option<CgroupStats>,
+    /// Samples taken while the process ran, if requested.
+    timeline: Option<Timeline>,
+    /// I/O counters of the process and its reaped children, if readable.
+    io: Option<ProcIo>,
 }
 
 impl Stats {
-    /// Check exit status and build valid resource usage data.
+    /// Check wait status and build valid resource usage data.
     pub(super) fn from_result(
         pid: Pid,
         result: i32,
         wstatus: i32,
         usage: libc::rusage,
-        wall_time: Duration,
-        monotonic_time: Duration,
+        real_time: Duration,
     ) -> Result<Self> {
         let wait_status = WaitStatus::from_raw(Pid::from_raw(result), wstatus)?;
         match wait_status.pid() {
@@ -73,25 +113,85 @@ impl Stats {
             None => bail!("no process measured"),
         }
 
+        let termination = match wait_status {
+            WaitStatus::Exited(_, code) => Termination::Exited(code),
+            WaitStatus::Signaled(_, signal, core_dumped) => Termination::Signaled { signal, core_dumped },
+            WaitStatus::Stopped(..)
+            | WaitStatus::PtraceEvent(..)
+            | WaitStatus::PtraceSyscall(_)
+            | WaitStatus::Continued(_)
+            | WaitStatus::StillAlive => bail!("process did not terminate: {wait_status:?}"),
+        };
+
         let exit_status = ExitStatus::from_raw(wstatus);
         log::trace!("usage: pid={pid}, wait_status={wait_status:?}, exit_status={exit_status:?}");
-        if exit_status.code().is_none() {
-            bail!("process did not exit, discarding usage");
-        }
 
         Ok(Self {
             usage,
             wait_status,
             exit_status,
-            wall_time,
-            monotonic_time,
+            termination,
+            real_time,
+            cgroup: None,
+            timeline: None,
+            io: None,
         })
     }
 
+    /// Attach usage measured by the cgroup where the process ran.
+    #[inline]
+    #[must_use]
+    pub(super) const fn with_cgroup(mut self, cgroup: CgroupStats) -> Self {
+        self.cgroup = Some(cgroup);
+        self
+    }
+
+    /// Attach I/O counters read before the process was reaped.
+    #[inline]
+    #[must_use]
+    pub(super) const fn with_io(mut self, io: ProcIo) -> Self {
+        self.io = Some(io);
+        self
+    }
+
+    /// I/O counters from `/proc/<pid>/io`, read right after the process exited.
+    ///
+    /// Unlike [`input_blocked`](Self::input_blocked) and [`output_blocked`](Self::output_blocked), this counts bytes,
+    /// including reads and writes served by the page cache. Includes children reaped by the process.
+    #[inline]
+    #[must_use]
+    pub const fn io(&self) -> Option<&ProcIo> {
+        self.io.as_ref()
+    }
+
+    /// Attach samples taken while the process ran.
+    #[inline]
+    #[must_use]
+    pub(super) fn with_timeline(mut self, timeline: Timeline) -> Self {
+        self.timeline = Some(timeline);
+        self
+    }
+
+    /// Memory, CPU and I/O over time, if the process was sampled.
+    #[inline]
+    #[must_use]
+    pub const fn timeline(&self) -> Option<&Timeline> {
+        self.timeline.as_ref()
+    }
+
+    /// Usage of the process and all of its descendants, if it ran in a transient cgroup.
+    ///
+    /// Unlike [`wait4`](super::wait4), this includes descendants that were not reaped by the process.
+    #[inline]
+    #[must_use]
+    pub const fn cgroup(&self) -> Option<&CgroupStats> {
+        self.cgroup.as_ref()
+    }
+
     #[cfg(test)]
     /// Child execution status when resource usage was measured.
     ///
-    /// Should always be [`WaitStatus::Exited`].
+    /// Either [`WaitStatus::Exited`] or [`WaitStatus::Signaled`].
     #[inline]
     #[must_use]
     pub const fn wait_status(&self) -> WaitStatus {
@@ -115,14 +215,22 @@ impl Stats {
         self.exit_status
     }
 
+    /// Exit code or signal that terminated the child process.
+    #[inline]
+    #[must_use]
+    pub const fn termination(&self) -> Termination {
+        self.termination
+    }
+
     #[cfg(test)]
     /// Final code resolved by child process.
     #[inline]
     #[must_use]
     pub fn exit_code(&self) -> i32 {
-        self.exit_status()
-            .code()
-            .unwrap_or_else(|| unreachable!("exit code was reasolved from exit status before"))
+        match self.termination() {
+            Termination::Exited(code) => code,
+            Termination::Signaled { signal, .. } => unreachable!("process was killed by {signal}"),
+        }
     }
 
     /// User CPU time used.
@@ -151,23 +259,55 @@ impl Stats {
         duration(self.usage.ru_stime)
     }
 
-    /// Elapsed virtual (CPU) time.
+    /// Total CPU time used.
+    ///
+    /// Taken from the cgroup when available, since it includes descendants that were not reaped. Otherwise, it is
+    /// [`user_time`](Self::user_time) plus [`system_time`](Self::system_time).
     ///
-    /// Measured with [`Instant`](std::time::Instant). Should be equivalent to [`user_time`](Self::user_time) plus
-    /// [`system_time`](Self::system_time).
+    /// # Panics
+    ///
+    /// If [`Duration`] overflows or a negative value was received.
     #[inline]
     #[must_use]
-    pub const fn virtual_time(&self) -> Duration {
-        self.monotonic_time
+    pub const fn cpu_time(&self) -> Duration {
+        if let Some(cgroup) = &self.cgroup {
+            return cgroup.cpu.usage;
+        }
+        self.user_time()
+            .checked_add(self.system_time())
+            .expect("CPU time overflowed")
     }
 
     /// Elapsed real (wall) time.
     ///
-    /// Measured with [`SystemTime`](std::time::SystemTime).
+    /// Measured with [`Instant`](std::time::Instant), so it is not affected by changes to the system clock.
     #[inline]
     #[must_use]
     pub const fn real_time(&self) -> Duration {
-        self.wall_time
+        self.real_time
+    }
+
+    /// Effective parallelism, as CPU time over real time.
+    ///
+    /// A single-threaded program stays below `1.0`. Zero if no real time elapsed.
+    #[inline]
+    #[must_use]
+    pub fn parallelism(&self) -> f64 {
+        let real_time = self.real_time().as_secs_f64();
+        if real_time > 0.0 {
+            self.cpu_time().as_secs_f64() / real_time
+        } else {
+            0.0
+        }
+    }
+
+    /// Parallel efficiency, as the [`parallelism`](Self::parallelism) per thread.
+    ///
+    /// Using the number of available CPUs gives the CPU utilization.
+    #[inline]
+    #[must_use]
+    pub fn efficiency(&self, threads: NonZeroU32) -> f64 {
+        self.parallelism() / f64::from(threads.get())
     }
 
     /// Maximum resident set size.
@@ -392,7 +532,7 @@ impl Stats {
 #[cfg(test)]
 mod tests {
     use std::thread::sleep;
-    use std::time::{Instant, SystemTime};
+    use std::time::Instant;
 
     use nix::errno::Errno;
 
@@ -402,7 +542,7 @@ mod tests {
     use super::*;
 
     fn mock_usage() -> Stats {
-        let (wall_time, monotonic_time) = (SystemTime::now(), Instant::now());
+        let start_time = Instant::now();
         sleep(Duration::from_millis(1));
 
         // SAFETY: libc structs can be zeroed
@@ -412,10 +552,9 @@ mod tests {
         Errno::result(res).unwrap();
 
         let pid = Pid::this();
-        let real_time = wall_time.elapsed().unwrap();
-        let virtual_time = monotonic_time.elapsed();
+        let real_time = start_time.elapsed();
 
-        Stats::from_result(pid, pid.as_raw(), 0x0080, usage, real_time, virtual_time).unwrap()
+        Stats::from_result(pid, pid.as_raw(), 0x0080, usage, real_time).unwrap()
     }
 
     #[test]
@@ -428,7 +567,7 @@ mod tests {
 
         assert_eq!(usage.user_time().as_secs(), 0);
         assert_eq!(usage.system_time().as_secs(), 0);
-        assert_eq!(usage.virtual_time().as_secs(), 0);
+        assert_eq!(usage.cpu_time(), usage.user_time() + usage.system_time());
         assert_eq!(usage.real_time().as_secs(), 0);
         assert_ne!(usage.real_time().as_nanos(), 0);
 
@@ -453,4 +592,46 @@ mod tests {
         assert_eq!(usage.ipc_msg_rcv(), 0);
         assert_eq!(usage.num_signals(), 0);
     }
+
+    #[test]
+    fn parallelism_from_cpu_time() {
+        // SAFETY: libc structs can be zeroed
+        let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+        usage.ru_utime.tv_sec = 3;
+        usage.ru_stime.tv_sec = 1;
+
+        let pid = Pid::this();
+        let stats = Stats::from_result(pid, pid.as_raw(), 0, usage, Duration::from_secs(2)).unwrap();
+        assert_eq!(stats.cpu_time(), Duration::from_secs(4));
+        assert_eq!(stats.parallelism().to_bits(), 2.0_f64.to_bits());
+        assert_eq!(stats.efficiency(NonZeroU32::new(4).unwrap()).to_bits(), 0.5_f64.to_bits());
+
+        let stats = Stats::from_result(pid, pid.as_raw(), 0, usage, Duration::ZERO).unwrap();
+        assert_eq!(stats.parallelism().to_bits(), 0.0_f64.to_bits(), "no real time elapsed");
+    }
+
+    #[test]
+    fn keeps_signaled_usage() {
+        // SAFETY: libc structs can be zeroed
+        let usage: libc::rusage = unsafe { std::mem::zeroed() };
+        let pid = Pid::this();
+
+        let stats = Stats::from_result(pid, pid.as_raw(), 0x0100, usage, Duration::ZERO).unwrap();
+        assert_eq!(stats.termination(), Termination::Exited(1));
+        assert_eq!(stats.termination().to_string(), "exited with status 1");
+
+        let stats = Stats::from_result(pid, pid.as_raw(), 0x0009, usage, Duration::ZERO).unwrap();
+        let termination = Termination::Signaled {
+            signal: Signal::SIGKILL,
+            core_dumped: false,
+        };
+        assert_eq!(stats.termination(), termination);
+        assert_eq!(stats.termination().to_string(), "killed by SIGKILL");
+
+        let stats = Stats::from_result(pid, pid.as_raw(), 0x008B, usage, Duration::ZERO).unwrap();
+        assert_eq!(stats.termination().to_string(), "killed by SIGSEGV (core dumped)");
+
+        let error = Stats::from_result(pid, pid.as_raw(), 0x137F, usage, Duration::ZERO).unwrap_err();
+        assert_eq!(error.to_string(), format!("process did not terminate: Stopped(Pid({pid}), SIGSTOP)"));
+    }
 }
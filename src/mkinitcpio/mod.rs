@@ -6,7 +6,7 @@ use std::path::{Path, PathBuf};
 use anyhow::Result;
 
 use crate::bash::BashString;
-use crate::measure::{self, Stats};
+use crate::measure::{Options, Stats};
 
 mod config;
 mod preset;
@@ -14,6 +14,44 @@ mod preset;
 pub use config::Config;
 pub use preset::Preset;
 
+/// Path to the `mkinitcpio` binary.
+pub const MKINITCPIO: &str = "/usr/bin/mkinitcpio";
+/// Default configuration file.
+const CONFIG_FILE: &str = "/etc/mkinitcpio.conf";
+/// Drop-in directory for the default configuration.
+const CONFIG_DROP_IN_DIR: &str = "/etc/mkinitcpio.conf.d";
+/// Directory of the preset files.
+const PRESET_DIR: &str = "/etc/mkinitcpio.d";
+
+/// Configuration and preset files that affect the images built for `presets`.
+///
+/// Files are not checked to exist.
+#[must_use]
+pub fn config_files(presets: &[Preset]) -> Vec<PathBuf> {
+    let list = |dir: &str| {
+        std::fs::read_dir(dir)
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| Some(entry.ok()?.path()))
+            .collect::<Vec<_>>()
+    };
+
+    let mut files = vec![PathBuf::from(CONFIG_FILE)];
+    files.extend(list(CONFIG_DROP_IN_DIR));
+    files.extend(
+        list(PRESET_DIR)
+            .into_iter()
+            .filter(|path| path.extension() == Some("preset".as_ref())),
+    );
+    files.extend(
+        presets
+            .iter()
+            .filter_map(|preset| preset.config.as_ref())
+            .map(|config| config.as_path().to_path_buf()),
+    );
+    files
+}
+
 /// Create a mock preset at `output_dir`.
 ///
 /// Returns the path to the new preset file.
@@ -115,9 +153,9 @@ fn cleanup(dir: &Path) -> Result<()> {
 /// # Errors
 ///
 /// Multiple reasons.
-pub fn mkinitcpio(preset: &Path) -> Result<Stats> {
+pub fn mkinitcpio(preset: &Path, options: &Options) -> Result<Stats> {
     log::trace!("mkinitcpio: preset={}", preset.display());
-    measure::exec("/usr/bin/mkinitcpio", ["--preset".as_ref(), preset.as_os_str()])
+    options.exec(MKINITCPIO, ["--preset".as_ref(), preset.as_os_str()])
 }
 
 #[cfg(test)]
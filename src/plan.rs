@@ -0,0 +1,165 @@
+//! Order of the benchmark jobs.
+//!
+//! Jobs run in a seeded random order, so thermal throttling and cache state don't favor the same methods on every
+//! run. The same seed gives back the same order.
+
+use std::fmt;
+
+use crate::compression::{COMPRESSION, Threads};
+
+/// Raw image compressed by a job.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Target {
+    /// Initramfs image.
+    Img,
+    /// Unified kernel image.
+    Uki,
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Img => "img",
+            Self::Uki => "uki",
+        })
+    }
+}
+
+/// A single compression and decompression of one image.
+///
+/// Ordered by preset, method, target and repetition, then by position in the thread sweep.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Job {
+    /// Index of the preset.
+    pub preset: usize,
+    /// Index of the method in [`COMPRESSION`].
+    pub compression: usize,
+    /// Image to compress.
+    pub target: Target,
+    /// Repetition number, starting at 1, when repeating the benchmark.
+    pub repetition: Option<u32>,
+    /// Position in the `--threads` sweep.
+    pub sweep: usize,
+    /// Compression threads, or the tool default.
+    pub threads: Option<Threads>,
+}
+
+impl Job {
+    /// Method name, with the number of threads.
+    #[must_use]
+    pub fn method(&self) -> String {
+        let name = COMPRESSION[self.compression].name;
+        self.threads
+            .map_or_else(|| name.to_owned(), |threads| format!("{name}@{threads}"))
+    }
+
+    /// Display name, as `preset/method/target`, with the repetition.
+    #[must_use]
+    pub fn name(&self, preset: &str) -> String {
+        let name = format!("{preset}/{}/{}", self.method(), self.target);
+        match self.repetition {
+            Some(repetition) => format!("{name}#{repetition}"),
+            None => name,
+        }
+    }
+
+    /// Extension for the copy of the raw image, unique for each job of a preset.
+    #[must_use]
+    pub fn suffix(&self) -> String {
+        let threads = self.threads.map(|threads| format!("@{threads}"));
+        let repetition = self.repetition.map(|repetition| format!("#{repetition}"));
+        format!(".{}{}{}", self.compression, threads.unwrap_or_default(), repetition.unwrap_or_default())
+    }
+
+    /// Both jobs are the same scenario, except for the number of threads.
+    #[must_use]
+    pub fn same_scenario(&self, other: &Self) -> bool {
+        (self.preset, self.compression, self.target, self.repetition)
+            == (other.preset, other.compression, other.target, other.repetition)
+    }
+}
+
+/// Jobs in the order they will run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Plan {
+    /// Seed for the order, to reproduce it.
+    pub seed: u64,
+    /// Jobs, in order.
+    pub jobs: Vec<Job>,
+}
+
+impl Plan {
+    /// Shuffle the jobs with a seeded random number generator.
+    #[must_use]
+    pub fn shuffled(mut jobs: Vec<Job>, seed: u64) -> Self {
+        jobs.sort_unstable();
+        fastrand::Rng::with_seed(seed).shuffle(&mut jobs);
+        Self { seed, jobs }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use test_log::test;
+
+    use super::*;
+
+    /// Jobs for two presets, all methods and both targets, repeated twice.
+    fn jobs() -> Vec<Job> {
+        let mut jobs = Vec::new();
+        for preset in 0..2 {
+            for compression in 0..COMPRESSION.len() {
+                for target in [Target::Img, Target::Uki] {
+                    for repetition in 1..=2 {
+                        jobs.push(Job {
+                            preset,
+                            compression,
+                            target,
+                            repetition: Some(repetition),
+                            sweep: 0,
+                            threads: None,
+                        });
+                    }
+                }
+            }
+        }
+        jobs
+    }
+
+    #[test]
+    fn same_seed_same_order() {
+        let plan = Plan::shuffled(jobs(), 42);
+        assert_eq!(plan, Plan::shuffled(jobs().into_iter().rev().collect(), 42), "independent of input order");
+        assert_ne!(plan.jobs, Plan::shuffled(jobs(), 43).jobs, "depends on the seed");
+        assert_ne!(plan.jobs, jobs(), "interleaved");
+
+        let mut sorted = plan.jobs;
+        sorted.sort_unstable();
+        assert_eq!(sorted, jobs(), "keeps all jobs");
+    }
+
+    #[test]
+    fn names_jobs() {
+        let job = Job {
+            preset: 0,
+            compression: 0,
+            target: Target::Uki,
+            repetition: Some(3),
+            sweep: 1,
+            threads: Some(Threads::All),
+        };
+        let method = COMPRESSION[0].name;
+        assert_eq!(job.name("linux"), format!("linux/{method}@all/uki#3"));
+        assert_eq!(job.suffix(), ".0@all#3");
+
+        let single = Job {
+            repetition: None,
+            threads: None,
+            ..job
+        };
+        assert_eq!(single.name("linux"), format!("linux/{method}/uki"));
+        assert_eq!(single.suffix(), ".0");
+        assert!(job.same_scenario(&Job { sweep: 0, ..job }), "only threads differ");
+    }
+}
@@ -0,0 +1,557 @@
+//! Results for each compression method, and how they are displayed.
+
+use std::fmt;
+use std::fs::File;
+use std::io::BufWriter;
+use std::num::NonZeroU32;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use byte_unit::{Byte, Unit, UnitType};
+
+use crate::cache::CacheMode;
+use crate::compression;
+use crate::environment::Environment;
+use crate::kernel::FrameHeader;
+use crate::measure::{Limits, Schedule, SignalError, Stats, TimeoutError};
+use crate::stats::{Comparison, Outliers, Test};
+use crate::utils::command::CommandError;
+use crate::utils::digest::Digest;
+
+/// Measurements for a single compression method on a single image.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Benchmark {
+    /// Display name, as `preset/method/target`.
+    pub name: String,
+    /// Page cache state before each command.
+    pub cache: CacheMode,
+    /// CPU affinity, scheduling and I/O priority of each command.
+    pub schedule: Schedule,
+    /// Threads used to compress.
+    pub threads: NonZeroU32,
+    /// System noise readings, taken before the benchmark.
+    pub environment: Environment,
+    /// Resource usage during compression.
+    pub compress: Stats,
+    /// Resource usage during decompression.
+    pub decompress: Stats,
+    /// Header of the compressed image.
+    pub header: FrameHeader,
+    /// Size of the raw image.
+    pub original_size: Byte,
+    /// Size of the compressed image.
+    pub compressed_size: Byte,
+    /// Digest of the raw image, before compression.
+    pub original: Digest,
+    /// Digest of the image recovered by decompression.
+    pub roundtrip: Digest,
+    /// Decompression under early-boot limits, if requested.
+    pub boot: Option<BootDecompress>,
+}
+
+impl Benchmark {
+    /// Decompression gave back the original image.
+    #[inline]
+    #[must_use]
+    pub fn is_lossless(&self) -> bool {
+        self.original == self.roundtrip
+    }
+}
+
+/// Decompression repeated under resource limits, like the kernel in early boot.
+///
+/// Failures here are expected for some methods and don't fail the benchmark.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BootDecompress {
+    /// Limits for the decompression.
+    pub limits: Limits,
+    /// Resource usage, or why the decompression failed under the limits.
+    pub result: Result<Stats, Failure>,
+}
+
+impl BootDecompress {
+    /// How much longer decompression took under the limits, compared to `unlimited`.
+    #[must_use]
+    pub fn slowdown(&self, unlimited: &Stats) -> Option<f64> {
+        let stats = self.result.as_ref().ok()?;
+        let base = unlimited.real_time().as_secs_f64();
+        (base > 0.0).then(|| stats.real_time().as_secs_f64() / base)
+    }
+}
+
+/// Step of a benchmark where a failure can happen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Phase {
+    /// Building the raw images and copying them for each method.
+    Setup,
+    /// Compressing the raw image.
+    Compress,
+    /// Decompressing the compressed image.
+    Decompress,
+    /// Decompressing again, under early-boot limits.
+    BootDecompress,
+    /// Reading headers and digests of the outputs.
+    Verify,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Setup => "setup",
+            Self::Compress => "compress",
+            Self::Decompress => "decompress",
+            Self::BootDecompress => "boot-decompress",
+            Self::Verify => "verify",
+        })
+    }
+}
+
+/// Error in one phase of a benchmark.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Failure {
+    /// Phase where the error happened.
+    pub phase: Phase,
+    /// Error message, with context.
+    pub message: String,
+    /// Command, exit status and stderr excerpt, if a command failed.
+    pub command: Option<CommandError>,
+    /// Time limit and measurements, if a command was killed for running too long.
+    pub timeout: Option<Box<TimeoutError>>,
+    /// Measurements of a command killed by a signal.
+    pub stats: Option<Box<Stats>>,
+}
+
+impl Failure {
+    /// Keep the error details for a phase.
+    #[must_use]
+    pub fn new(phase: Phase, error: &anyhow::Error) -> Self {
+        Self {
+            phase,
+            message: format!("{error:#}"),
+            command: error.downcast_ref::<CommandError>().cloned(),
+            timeout: error.downcast_ref::<TimeoutError>().cloned().map(Box::new),
+            stats: error
+                .downcast_ref::<SignalError>()
+                .map(|error| Box::new(error.stats.clone())),
+        }
+    }
+
+    /// Adapter for [`Result::map_err`].
+    pub fn at(phase: Phase) -> impl FnOnce(anyhow::Error) -> Self {
+        move |error| Self::new(phase, &error)
+    }
+}
+
+/// Result of testing a compression method.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    /// Method was measured on a single image.
+    Measured(Box<Benchmark>),
+    /// Method failed on a single image.
+    Failed {
+        /// Display name, as `preset/method/target`.
+        name: String,
+        /// What went wrong.
+        failure: Failure,
+        /// Resource usage during compression, if it finished.
+        compress: Option<Box<Stats>>,
+    },
+    /// Command was killed after running longer than its time limit.
+    TimedOut {
+        /// Display name, as `preset/method/target`.
+        name: String,
+        /// Phase where the command was killed.
+        phase: Phase,
+        /// Time limit and resource usage until the command was killed.
+        error: Box<TimeoutError>,
+        /// Resource usage during compression, if it finished.
+        compress: Option<Box<Stats>>,
+    },
+    /// Method was not tested for the preset.
+    Skipped {
+        /// Display name, as `preset/method`.
+        name: String,
+        /// Why the method could not be tested.
+        reason: String,
+    },
+}
+
+impl Outcome {
+    /// Build a [`TimedOut`](Self::TimedOut) outcome when the failure was caused by a timeout, otherwise
+    /// [`Failed`](Self::Failed).
+    #[must_use]
+    pub fn failed(name: String, mut failure: Failure, compress: Option<Box<Stats>>) -> Self {
+        match failure.timeout.take() {
+            Some(error) => Self::TimedOut {
+                name,
+                phase: failure.phase,
+                error,
+                compress,
+            },
+            None => Self::Failed {
+                name,
+                failure,
+                compress,
+            },
+        }
+    }
+
+    /// Method was skipped or measured without errors.
+    #[inline]
+    #[must_use]
+    pub fn is_ok(&self) -> bool {
+        match self {
+            Self::Measured(benchmark) => benchmark.is_lossless(),
+            Self::Failed { .. } | Self::TimedOut { .. } => false,
+            Self::Skipped { .. } => true,
+        }
+    }
+}
+
+/// Display a summary of failed benchmarks, if any.
+pub fn log_summary(outcomes: &[Outcome]) {
+    let failed = outcomes.iter().filter(|outcome| !outcome.is_ok()).count();
+    let skipped = outcomes
+        .iter()
+        .filter(|outcome| matches!(outcome, Outcome::Skipped { .. }))
+        .count();
+    if failed == 0 {
+        log::info!("summary: {} results, {skipped} skipped, no failures", outcomes.len());
+        return;
+    }
+
+    log::error!("summary: {} results, {skipped} skipped, {failed} failed", outcomes.len());
+    for outcome in outcomes {
+        match outcome {
+            Outcome::Measured(benchmark) if !benchmark.is_lossless() => {
+                log::error!("{}: {} failed: decompressed image differs", benchmark.name, Phase::Verify);
+            }
+            Outcome::Failed { name, failure, .. } => {
+                log::error!("{name}: {} failed: {}", failure.phase, failure.message);
+                if let Some(command) = &failure.command {
+                    log::error!("{name}: command={}, status={}", command.name, command.status);
+                }
+                if let Some(stats) = &failure.stats {
+                    log::error!("{name}: {}", stats.termination());
+                    log_stats(&format!("{name}/{}", failure.phase), stats, None);
+                }
+            }
+            Outcome::TimedOut { name, phase, error, .. } => {
+                log::error!("{name}: {phase} timed out after {:?}", error.timeout);
+                log_stats(&format!("{name}/{phase}"), &error.stats, None);
+            }
+            Outcome::Measured(_) | Outcome::Skipped { .. } => (),
+        }
+    }
+}
+
+/// Display how each method behaved under early-boot limits, compared to unlimited decompression.
+pub fn log_boot_summary(outcomes: &[Outcome]) {
+    for outcome in outcomes {
+        let Outcome::Measured(benchmark) = outcome else {
+            continue;
+        };
+        let Some(boot) = &benchmark.boot else {
+            continue;
+        };
+
+        let name = &benchmark.name;
+        match &boot.result {
+            Ok(stats) => log::info!(
+                "{name}: {}: {:.2}x slower under {} ({:?} vs {:?})",
+                Phase::BootDecompress,
+                boot.slowdown(&benchmark.decompress).unwrap_or(f64::NAN),
+                boot.limits,
+                stats.real_time(),
+                benchmark.decompress.real_time()
+            ),
+            Err(failure) => {
+                log::warn!("{name}: {}: failed under {}: {}", Phase::BootDecompress, boot.limits, failure.message);
+            }
+        }
+    }
+}
+
+/// Display compressed and original sizes.
+pub fn log_size(benchmark: &Benchmark) {
+    log::info!(
+        "{}: Compressed size: {} (original: {})",
+        benchmark.name,
+        benchmark.compressed_size.get_appropriate_unit(UnitType::Binary),
+        benchmark.original_size.get_appropriate_unit(UnitType::Binary)
+    );
+}
+
+/// Display how the same method scales with the number of compression threads.
+///
+/// Times and peak memory are compared to the first measured thread count, along with any change of compressed size.
+pub fn log_scaling<'a>(name: &str, outcomes: impl IntoIterator<Item = &'a Outcome>) {
+    let benchmarks: Vec<_> = outcomes
+        .into_iter()
+        .filter_map(|outcome| match outcome {
+            Outcome::Measured(benchmark) => Some(benchmark),
+            Outcome::Failed { .. } | Outcome::TimedOut { .. } | Outcome::Skipped { .. } => None,
+        })
+        .collect();
+    let Some(base) = benchmarks.first() else {
+        return;
+    };
+
+    let ratio = |value: f64, base: f64| if base > 0.0 { value / base } else { f64::NAN };
+    for benchmark in &benchmarks {
+        let (stats, base_stats) = (&benchmark.compress, &base.compress);
+        let size = if benchmark.compressed_size >= base.compressed_size {
+            format!(
+                "+{}",
+                benchmark
+                    .compressed_size
+                    .subtract(base.compressed_size)
+                    .unwrap_or(Byte::MIN)
+            )
+        } else {
+            format!(
+                "-{}",
+                base.compressed_size
+                    .subtract(benchmark.compressed_size)
+                    .unwrap_or(Byte::MIN)
+            )
+        };
+        log::info!(
+            "{name}: Scaling: threads={}, real={:?} ({:.2}x), cpu={:?} ({:.2}x), peak={} ({:.2}x), size={} ({size})",
+            benchmark.threads,
+            stats.real_time(),
+            ratio(stats.real_time().as_secs_f64(), base_stats.real_time().as_secs_f64()),
+            stats.cpu_time(),
+            ratio(stats.cpu_time().as_secs_f64(), base_stats.cpu_time().as_secs_f64()),
+            peak_memory(stats).get_appropriate_unit(UnitType::Binary),
+            ratio(as_f64(peak_memory(stats)), as_f64(peak_memory(base_stats))),
+            benchmark.compressed_size.get_appropriate_unit(UnitType::Binary)
+        );
+    }
+}
+
+/// Name, value, display scale and unit of a measurement compared between methods.
+type Metric = (&'static str, fn(&Benchmark) -> f64, f64, &'static str);
+
+/// Measurements compared between repetitions and methods.
+const METRICS: [Metric; 3] = [
+    ("compress", |benchmark| benchmark.compress.real_time().as_secs_f64(), 1000.0, "ms"),
+    ("decompress", |benchmark| benchmark.decompress.real_time().as_secs_f64(), 1000.0, "ms"),
+    ("size", |benchmark| as_f64(benchmark.compressed_size), 1.0 / 1024.0, " KiB"),
+];
+
+/// Display whether the methods of a scenario differ, for each pair of methods and each metric.
+///
+/// `methods` are the repeated benchmarks of each method. Outliers are reported for each method first, and left out
+/// of the tests if excluded. Pairs with fewer benchmarks than [`Test::min_samples`] on either side are skipped.
+pub fn log_significance(name: &str, methods: &[(String, Vec<&Benchmark>)], test: Test, outliers: Outliers) {
+    let samples: Vec<_> = methods
+        .iter()
+        .map(|(method, benchmarks)| log_outliers(&format!("{name}: {method}"), benchmarks, outliers))
+        .collect();
+
+    for (index, ((first, _), first_samples)) in methods.iter().zip(&samples).enumerate() {
+        for ((second, _), second_samples) in methods.iter().zip(&samples).skip(index + 1) {
+            let results: Vec<_> = METRICS
+                .iter()
+                .zip(first_samples.iter().zip(second_samples))
+                .filter_map(|(&(metric, _, scale, unit), (a, b))| {
+                    let comparison = Comparison::new(test, a, b)?;
+                    let marker = if comparison.is_significant() {
+                        ""
+                    } else {
+                        " (not significant)"
+                    };
+                    Some(format!(
+                        "{metric}={:+.1}{unit} [{:+.1}, {:+.1}] p={:.3}{marker}",
+                        comparison.difference * scale,
+                        comparison.interval.0 * scale,
+                        comparison.interval.1 * scale,
+                        comparison.p_value,
+                    ))
+                })
+                .collect();
+            if !results.is_empty() {
+                log::info!("{name}: Significance ({test}): {second} vs {first}: {}", results.join(", "));
+            }
+        }
+    }
+}
+
+/// Display how many repetitions of a method are outliers for each metric, with their raw values.
+///
+/// Returns the values of each metric in [`METRICS`] order, without the outliers if they are excluded.
+fn log_outliers(name: &str, benchmarks: &[&Benchmark], outliers: Outliers) -> Vec<Vec<f64>> {
+    let mut counts = Vec::with_capacity(METRICS.len());
+    let samples = METRICS
+        .iter()
+        .map(|&(metric, value, scale, unit)| {
+            let sample: Vec<_> = benchmarks.iter().map(|benchmark| value(benchmark)).collect();
+            let flags = outliers.flag(&sample);
+            for ((benchmark, value), _) in benchmarks.iter().zip(&sample).zip(&flags).filter(|(_, flag)| **flag) {
+                log::info!("{}: Outlier: {metric}={:.1}{unit}", benchmark.name, value * scale);
+            }
+            counts.push(format!("{metric}={}/{}", flags.iter().filter(|flag| **flag).count(), sample.len()));
+            outliers.filter(&sample)
+        })
+        .collect();
+
+    let action = if outliers.exclude { "excluded" } else { "kept" };
+    log::info!("{name}: Outliers ({}, {action}): {}", outliers.method, counts.join(", "));
+    samples
+}
+
+/// Highest memory usage of a command, from its cgroup when available.
+fn peak_memory(stats: &Stats) -> Byte {
+    stats
+        .cgroup()
+        .and_then(|cgroup| cgroup.memory_peak)
+        .unwrap_or_else(|| stats.max_rss())
+}
+
+/// Number of bytes, for ratios.
+pub fn as_f64(bytes: Byte) -> f64 {
+    bytes.get_adjusted_unit(Unit::B).get_value()
+}
+
+/// Display round-trip verification.
+pub fn log_digests(benchmark: &Benchmark) {
+    let name = &benchmark.name;
+    if benchmark.is_lossless() {
+        log::info!("{name}: Round-trip: ok (sha256={})", benchmark.original);
+    } else {
+        log::error!(
+            "{name}: Round-trip: decompressed image differs (original={}, roundtrip={})",
+            benchmark.original,
+            benchmark.roundtrip
+        );
+    }
+}
+
+/// Display frame header and flag images the kernel may fail to decompress.
+pub fn log_header(benchmark: &Benchmark, memory_limit: Byte) {
+    let name = &benchmark.name;
+    log::info!("{name}: Frame header: {}", benchmark.header);
+
+    if let Some(issue) = benchmark.header.boot_issue() {
+        log::warn!("{name}: Frame header: won't boot, {issue}");
+    }
+    if let Some(memory) = benchmark.header.decompression_memory()
+        && memory > memory_limit
+    {
+        log::warn!(
+            "{name}: Frame header: needs {} to decompress, above the limit of {}",
+            memory.get_appropriate_unit(UnitType::Binary),
+            memory_limit.get_appropriate_unit(UnitType::Binary)
+        );
+    }
+}
+
+/// Display statistics.
+///
+/// Parallel efficiency is shown when the number of `threads` used by the command is known.
+pub fn log_stats(name: &str, stats: &Stats, threads: Option<NonZeroU32>) {
+    let cpus = compression::available_cpus();
+    log::info!("{name}: Real time: {:?}", stats.real_time());
+    // same source as the total, which comes from the cgroup when available
+    let (user, system) = stats
+        .cgroup()
+        .map_or_else(|| (stats.user_time(), stats.system_time()), |cgroup| (cgroup.cpu.user, cgroup.cpu.system));
+    log::info!("{name}: CPU time: {:?} (usr: {user:?}) (sys: {system:?})", stats.cpu_time());
+    log::info!(
+        "{name}: Parallelism: {:.2}x (utilization: {:.1}% of {cpus} CPUs)",
+        stats.parallelism(),
+        100.0 * stats.efficiency(cpus)
+    );
+    if let Some(threads) = threads {
+        log::info!("{name}: Parallel efficiency: {:.1}% (threads={threads})", 100.0 * stats.efficiency(threads));
+    }
+    log::info!("{name}: Maximum memory: {}", stats.max_rss().get_appropriate_unit(UnitType::Decimal));
+    log::info!("{name}: Page faults: (minor={}, major={})", stats.minor_page_faults(), stats.major_page_faults());
+    log::info!("{name}: Block operations: (input={}, output={})", stats.input_blocked(), stats.output_blocked());
+    log::info!(
+        "{name}: Context switches: (voluntary={}, involuntary={})",
+        stats.num_vol_ctx_sw(),
+        stats.num_inv_ctx_sw()
+    );
+
+    if let Some(io) = stats.io() {
+        log::info!(
+            "{name}: I/O bytes: (read={}, write={}, rchar={}, wchar={})",
+            Byte::from_u64(io.read_bytes).get_appropriate_unit(UnitType::Decimal),
+            Byte::from_u64(io.write_bytes).get_appropriate_unit(UnitType::Decimal),
+            Byte::from_u64(io.rchar).get_appropriate_unit(UnitType::Decimal),
+            Byte::from_u64(io.wchar).get_appropriate_unit(UnitType::Decimal)
+        );
+        log::info!("{name}: I/O syscalls: (read={}, write={})", io.syscr, io.syscw);
+    }
+    if let Some(timeline) = stats.timeline()
+        && let Some(peak) = timeline.peak_rss()
+    {
+        log::info!(
+            "{name}: Sampled memory: peak {} in {} samples every {:?}",
+            peak.get_appropriate_unit(UnitType::Decimal),
+            timeline.samples.len(),
+            timeline.interval
+        );
+    }
+
+    if let Some(cgroup) = stats.cgroup() {
+        log::info!(
+            "{name}: Cgroup CPU time: {:?} (usr: {:?}) (sys: {:?})",
+            cgroup.cpu.usage,
+            cgroup.cpu.user,
+            cgroup.cpu.system
+        );
+        if cgroup.cpu.periods > 0 {
+            log::info!(
+                "{name}: Cgroup throttling: {:?} (throttled={}, periods={})",
+                cgroup.cpu.throttled,
+                cgroup.cpu.throttled_periods,
+                cgroup.cpu.periods
+            );
+        }
+        if let Some(peak) = cgroup.memory_peak {
+            log::info!("{name}: Cgroup peak memory: {}", peak.get_appropriate_unit(UnitType::Decimal));
+        }
+        if let Some(oom_kills) = cgroup.oom_kills
+            && oom_kills > 0
+        {
+            log::warn!("{name}: Cgroup OOM kills: {oom_kills}");
+        }
+        if let Some(io) = &cgroup.io {
+            log::info!(
+                "{name}: Cgroup I/O: (read={}, write={}, rios={}, wios={})",
+                io.read_bytes.get_appropriate_unit(UnitType::Decimal),
+                io.write_bytes.get_appropriate_unit(UnitType::Decimal),
+                io.read_ios,
+                io.write_ios
+            );
+        }
+    }
+}
+
+/// Write the timeline of a command as CSV under `dir`, if it was sampled.
+///
+/// Files are named after the benchmark and phase, like `default/zstd-fast/img.compress.csv`.
+///
+/// # Errors
+///
+/// Could not create or write the file.
+pub fn export_timeline(dir: &Path, name: &str, phase: Phase, stats: &Stats) -> Result<()> {
+    let Some(timeline) = stats.timeline() else {
+        return Ok(());
+    };
+
+    let path = dir.join(format!("{name}.{phase}.csv"));
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).with_context(|| format!("could not create {}", parent.display()))?;
+    }
+    let file = File::create(&path).with_context(|| format!("could not create {}", path.display()))?;
+    timeline
+        .write_csv(BufWriter::new(file))
+        .with_context(|| format!("could not write {}", path.display()))?;
+
+    log::debug!("{name}: {phase} timeline: {} samples in {}", timeline.samples.len(), path.display());
+    Ok(())
+}
@@ -0,0 +1,297 @@
+//! Timestamped directory for the outputs of each run.
+//!
+//! Runs are kept side by side in the output directory, like `output/20250101T120000Z`, with a `latest` symlink to
+//! the most recent one.
+
+use std::fs::File;
+use std::io::{self, IsTerminal, Write};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::SystemTime;
+
+use anyhow::{Context, Result};
+use env_logger::WriteStyle;
+
+/// Name of the symlink to the most recent run.
+pub const LATEST: &str = "latest";
+/// Name of the log file inside each run directory.
+pub const LOG_FILE: &str = "run.log";
+
+/// Output directory of a single run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunDir {
+    /// Path to the directory.
+    path: PathBuf,
+}
+
+impl RunDir {
+    /// Create a new directory under `outdir`, named after the `started` time.
+    ///
+    /// Runs started in the same second get a numeric suffix, like `20250101T120000Z-1`.
+    ///
+    /// # Errors
+    ///
+    /// Could not create the directory.
+    pub fn create(outdir: &Path, started: SystemTime) -> Result<Self> {
+        let timestamp: String = humantime::format_rfc3339_seconds(started)
+            .to_string()
+            .chars()
+            .filter(|ch| !matches!(ch, '-' | ':'))
+            .collect();
+
+        let mut path = outdir.join(&timestamp);
+        for suffix in 1.. {
+            if !path.exists() {
+                break;
+            }
+            path = outdir.join(format!("{timestamp}-{suffix}"));
+        }
+        std::fs::create_dir_all(&path).with_context(|| format!("could not create {}", path.display()))?;
+
+        log::info!("run directory: {}", path.display());
+        Ok(Self { path })
+    }
+
+    /// Path to the directory.
+    #[inline]
+    #[must_use]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Name of the directory, identifying the run.
+    #[must_use]
+    pub fn id(&self) -> String {
+        self.path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    }
+
+    /// Point the `latest` symlink in the parent directory to this run.
+    ///
+    /// # Errors
+    ///
+    /// Could not create or replace the symlink.
+    pub fn link_latest(&self) -> Result<()> {
+        let (Some(outdir), Some(name)) = (self.path.parent(), self.path.file_name()) else {
+            return Ok(());
+        };
+
+        // replaced atomically, so `latest` is never missing
+        let latest = outdir.join(LATEST);
+        let temporary = outdir.join(format!(".{LATEST}.{}", std::process::id()));
+        std::os::unix::fs::symlink(name, &temporary)
+            .with_context(|| format!("could not create {}", temporary.display()))?;
+        std::fs::rename(&temporary, &latest).with_context(|| format!("could not replace {}", latest.display()))?;
+        Ok(())
+    }
+}
+
+/// Remove the oldest run directories under `outdir`, keeping the last `keep`.
+///
+/// Only directories named like a [`RunDir`] are considered. Returns the removed directories.
+///
+/// # Errors
+///
+/// Could not list or remove the directories.
+pub fn prune(outdir: &Path, keep: NonZeroUsize) -> Result<Vec<PathBuf>> {
+    let mut runs = Vec::new();
+    for entry in std::fs::read_dir(outdir).with_context(|| format!("could not list {}", outdir.display()))? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir()
+            && let Some(name) = entry.file_name().to_str()
+            && let Some((timestamp, suffix)) = parse_run_name(name)
+        {
+            runs.push(((timestamp.to_owned(), suffix), entry.path()));
+        }
+    }
+    // `-10` comes after `-9`
+    runs.sort_unstable();
+
+    let count = runs.len().saturating_sub(keep.get());
+    let removed: Vec<_> = runs.into_iter().take(count).map(|(_, path)| path).collect();
+    for run in &removed {
+        log::info!("removing old run: {}", run.display());
+        std::fs::remove_dir_all(run).with_context(|| format!("could not remove {}", run.display()))?;
+    }
+    Ok(removed)
+}
+
+/// Timestamp and numeric suffix of a name like `20250101T120000Z`, with an optional `-N` suffix.
+fn parse_run_name(name: &str) -> Option<(&str, u32)> {
+    let (timestamp, suffix) = name.split_once('-').unwrap_or((name, "0"));
+    let bytes = timestamp.as_bytes();
+    let valid = bytes.len() == 16
+        && bytes[..8].iter().all(u8::is_ascii_digit)
+        && bytes[8] == b'T'
+        && bytes[9..15].iter().all(u8::is_ascii_digit)
+        && bytes[15] == b'Z';
+    if !valid {
+        return None;
+    }
+    Some((timestamp, suffix.parse().ok()?))
+}
+
+/// Log writer copying everything to stderr and to the log file of the run.
+///
+/// Lines logged before the file is opened are kept in memory and written when it opens. Styles are only kept on
+/// stderr, see [`LogTee::write_style`].
+#[derive(Debug, Clone, Default)]
+pub struct LogTee {
+    /// Log file, or the lines logged before it was opened.
+    file: Arc<Mutex<LogFile>>,
+}
+
+/// State of the log file.
+#[derive(Debug)]
+enum LogFile {
+    /// Not opened yet, keeping the early lines.
+    Pending(Vec<u8>),
+    /// Open file.
+    Open(File),
+}
+
+impl Default for LogFile {
+    fn default() -> Self {
+        Self::Pending(Vec::new())
+    }
+}
+
+impl LogTee {
+    /// Style for the log, like the default for stderr, since `env_logger` never styles a pipe by itself.
+    ///
+    /// Styled when stderr is a terminal, unless [`NO_COLOR`](https://no-color.org/) is set.
+    #[must_use]
+    pub fn write_style() -> WriteStyle {
+        let no_color = std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+        if io::stderr().is_terminal() && !no_color {
+            WriteStyle::Always
+        } else {
+            WriteStyle::Never
+        }
+    }
+
+    /// Start copying the log to `path`, writing the lines logged so far.
+    ///
+    /// # Errors
+    ///
+    /// Could not create or write the file.
+    pub fn open(&self, path: &Path) -> Result<()> {
+        let mut file = File::create(path).with_context(|| format!("could not create {}", path.display()))?;
+        let mut state = self.file.lock().unwrap_or_else(PoisonError::into_inner);
+        if let LogFile::Pending(lines) = &*state {
+            file.write_all(lines)
+                .with_context(|| format!("could not write {}", path.display()))?;
+        }
+        *state = LogFile::Open(file);
+        drop(state);
+        Ok(())
+    }
+}
+
+impl Write for LogTee {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        io::stderr().write_all(buf)?;
+        let plain = strip_styles(buf);
+        let mut state = self.file.lock().unwrap_or_else(PoisonError::into_inner);
+        match &mut *state {
+            LogFile::Pending(lines) => lines.extend_from_slice(&plain),
+            // a full disk shouldn't stop the benchmark
+            LogFile::Open(file) => drop(file.write_all(&plain)),
+        }
+        drop(state);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()?;
+        let mut state = self.file.lock().unwrap_or_else(PoisonError::into_inner);
+        match &mut *state {
+            LogFile::Open(file) => file.flush(),
+            LogFile::Pending(_) => Ok(()),
+        }
+    }
+}
+
+/// Remove ANSI escape sequences, like `\x1b[1;31m`, from log lines.
+fn strip_styles(buf: &[u8]) -> Vec<u8> {
+    let mut plain = Vec::with_capacity(buf.len());
+    let mut bytes = buf.iter().copied().peekable();
+    while let Some(byte) = bytes.next() {
+        if byte == 0x1b && bytes.next_if_eq(&b'[').is_some() {
+            // parameters until a final byte in `@` to `~`
+            bytes.find(|byte| (0x40..=0x7e).contains(byte));
+        } else {
+            plain.push(byte);
+        }
+    }
+    plain
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use pretty_assertions::assert_eq;
+    use test_log::test;
+
+    use super::*;
+
+    #[test]
+    fn creates_and_prunes_runs() {
+        let outdir = tempfile::tempdir().unwrap();
+        let started = SystemTime::UNIX_EPOCH + Duration::from_hours(482_148);
+
+        let first = RunDir::create(outdir.path(), started).unwrap();
+        assert_eq!(first.path(), outdir.path().join("20250101T120000Z"));
+        let second = RunDir::create(outdir.path(), started).unwrap();
+        assert_eq!(second.path(), outdir.path().join("20250101T120000Z-1"));
+        assert_eq!(second.id(), "20250101T120000Z-1");
+        let third = RunDir::create(outdir.path(), started + Duration::from_mins(1)).unwrap();
+        std::fs::create_dir_all(outdir.path().join("linux")).unwrap();
+
+        third.link_latest().unwrap();
+        let latest = outdir.path().join(LATEST);
+        assert_eq!(std::fs::read_link(&latest).unwrap(), Path::new("20250101T120100Z"));
+        second.link_latest().unwrap();
+        assert_eq!(std::fs::read_link(&latest).unwrap(), Path::new("20250101T120000Z-1"));
+
+        let removed = prune(outdir.path(), NonZeroUsize::new(2).unwrap()).unwrap();
+        assert_eq!(removed, [first.path()]);
+        assert!(second.path().is_dir(), "kept");
+        assert!(third.path().is_dir(), "kept");
+        assert!(outdir.path().join("linux").is_dir(), "not a run directory");
+
+        let ninth = outdir.path().join("20250101T120100Z-9");
+        let tenth = outdir.path().join("20250101T120100Z-10");
+        std::fs::create_dir_all(&ninth).unwrap();
+        std::fs::create_dir_all(&tenth).unwrap();
+        let removed = prune(outdir.path(), NonZeroUsize::new(1).unwrap()).unwrap();
+        assert_eq!(removed, [second.path().to_owned(), third.path().to_owned(), ninth], "numeric order of suffixes");
+        assert!(tenth.is_dir(), "latest kept");
+    }
+
+    #[test]
+    fn recognizes_run_names() {
+        assert_eq!(parse_run_name("20250101T120000Z"), Some(("20250101T120000Z", 0)), "timestamp");
+        assert_eq!(parse_run_name("20250101T120000Z-12"), Some(("20250101T120000Z", 12)), "with suffix");
+        assert_eq!(parse_run_name("latest"), None, "symlink");
+        assert_eq!(parse_run_name("20250101T120000Z-x"), None, "invalid suffix");
+        assert_eq!(parse_run_name("2025-01-01T12:00:00Z"), None, "not compact");
+    }
+
+    #[test]
+    fn copies_early_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(LOG_FILE);
+        let mut tee = LogTee::default();
+
+        tee.write_all(b"before\n").unwrap();
+        tee.open(&path).unwrap();
+        tee.write_all(b"\x1b[1m\x1b[33mWARN\x1b[0m after\n").unwrap();
+        tee.flush().unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "before\nWARN after\n", "styles only on stderr");
+    }
+}
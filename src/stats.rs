@@ -0,0 +1,547 @@
+//! Statistical tests and outlier detection for repeated measurements.
+//!
+//! Two methods within a few milliseconds of each other may only differ by noise. With `--repeat`, each pair of
+//! methods is tested so that such differences can be told apart from real ones.
+//!
+//! A single repetition disturbed by another process can also skew a mean, so outliers are flagged in each sample
+//! and can be left out of the tests.
+
+use std::fmt;
+
+use clap::ValueEnum;
+
+/// Significance level of the tests, for 95% confidence intervals.
+pub const ALPHA: f64 = 0.05;
+/// Largest sample count for the exact distribution of the Mann-Whitney U statistic.
+const MAX_EXACT_SAMPLES: usize = 40;
+/// Smallest count in each sample where the Mann-Whitney U test can reach [`ALPHA`], with `p = 2 / C(8, 4) ≈ 0.029`.
+const MIN_MANN_WHITNEY_SAMPLES: usize = 4;
+/// Distance from the quartiles, in interquartile ranges, beyond which a value is an outlier.
+const IQR_FENCE: f64 = 1.5;
+/// Modified z-score beyond which a value is an outlier, from Iglewicz and Hoaglin.
+const MAX_MODIFIED_Z: f64 = 3.5;
+/// Ratio between the median absolute deviation and the standard deviation of a normal distribution.
+const MAD_SCALE: f64 = 0.6745;
+/// Smallest sample where outliers are looked for.
+const MIN_OUTLIER_SAMPLES: usize = 3;
+
+/// Test deciding whether two samples differ.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, ValueEnum)]
+pub enum Test {
+    /// Welch's t-test, comparing the means without assuming equal variances.
+    #[default]
+    Welch,
+    /// Mann-Whitney U test, comparing the ranks, robust to outliers.
+    MannWhitney,
+}
+
+impl Test {
+    /// Smallest count in each sample where a difference can be significant.
+    ///
+    /// Below 4 values, even fully separated samples give a Mann-Whitney p-value of at least 0.1.
+    #[must_use]
+    pub const fn min_samples(self) -> usize {
+        match self {
+            Self::Welch => 2,
+            Self::MannWhitney => MIN_MANN_WHITNEY_SAMPLES,
+        }
+    }
+}
+
+impl fmt::Display for Test {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Welch => "welch",
+            Self::MannWhitney => "mann-whitney",
+        })
+    }
+}
+
+/// Rule flagging a value as an outlier in a sample.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, ValueEnum)]
+pub enum OutlierMethod {
+    /// Outside 1.5 interquartile ranges from the quartiles.
+    #[default]
+    Iqr,
+    /// Modified z-score above 3.5, from the median absolute deviation.
+    Mad,
+}
+
+impl fmt::Display for OutlierMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Iqr => "iqr",
+            Self::Mad => "mad",
+        })
+    }
+}
+
+/// How outliers in repeated measurements are handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Outliers {
+    /// Rule flagging the outliers.
+    pub method: OutlierMethod,
+    /// Leave the outliers out of tests and median times, instead of only reporting them.
+    pub exclude: bool,
+}
+
+impl Outliers {
+    /// Flag each value of `sample` that is an outlier.
+    ///
+    /// Nothing is flagged in samples of less than 3 values.
+    #[must_use]
+    pub fn flag(self, sample: &[f64]) -> Vec<bool> {
+        let mut sorted = sample.to_vec();
+        sorted.sort_unstable_by(f64::total_cmp);
+        if sorted.len() < MIN_OUTLIER_SAMPLES {
+            return vec![false; sample.len()];
+        }
+
+        match self.method {
+            OutlierMethod::Iqr => {
+                let (first, third) = (quantile(&sorted, 1, 4), quantile(&sorted, 3, 4));
+                let fence = IQR_FENCE * (third - first);
+                sample
+                    .iter()
+                    .map(|value| *value < first - fence || *value > third + fence)
+                    .collect()
+            }
+            OutlierMethod::Mad => {
+                let median = quantile(&sorted, 1, 2);
+                let mut deviations: Vec<_> = sample.iter().map(|value| (value - median).abs()).collect();
+                deviations.sort_unstable_by(f64::total_cmp);
+                let deviation = quantile(&deviations, 1, 2);
+                sample
+                    .iter()
+                    .map(|value| deviation > 0.0 && MAD_SCALE * (value - median).abs() / deviation > MAX_MODIFIED_Z)
+                    .collect()
+            }
+        }
+    }
+
+    /// Values of `sample` kept in tests and median times, without the outliers if they are excluded.
+    #[must_use]
+    pub fn filter(self, sample: &[f64]) -> Vec<f64> {
+        if !self.exclude {
+            return sample.to_vec();
+        }
+        sample
+            .iter()
+            .zip(self.flag(sample))
+            .filter_map(|(value, outlier)| (!outlier).then_some(*value))
+            .collect()
+    }
+}
+
+/// Difference between two samples.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Comparison {
+    /// Mean of the second sample minus mean of the first.
+    pub difference: f64,
+    /// Confidence interval of the difference, at `1 - ALPHA`.
+    pub interval: (f64, f64),
+    /// Probability of a difference at least this large if the samples don't differ.
+    pub p_value: f64,
+}
+
+impl Comparison {
+    /// Compare sample `b` to sample `a` with `test`, if both have at least [`Test::min_samples`] values.
+    ///
+    /// The confidence interval always comes from Welch's t-test.
+    #[must_use]
+    pub fn new(test: Test, a: &[f64], b: &[f64]) -> Option<Self> {
+        if a.len().min(b.len()) < test.min_samples() {
+            return None;
+        }
+        let welch = Welch::new(a, b)?;
+        let p_value = match test {
+            Test::Welch => welch.p_value,
+            Test::MannWhitney => MannWhitney::new(a, b)?.p_value,
+        };
+        Some(Self {
+            difference: welch.difference,
+            interval: welch.interval,
+            p_value,
+        })
+    }
+
+    /// The difference is unlikely to come from noise alone.
+    #[inline]
+    #[must_use]
+    pub fn is_significant(&self) -> bool {
+        self.p_value < ALPHA
+    }
+}
+
+/// Welch's t-test between two samples.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Welch {
+    /// Mean of the second sample minus mean of the first.
+    pub difference: f64,
+    /// Confidence interval of the difference, at `1 - ALPHA`.
+    pub interval: (f64, f64),
+    /// Two-sided p-value.
+    pub p_value: f64,
+}
+
+impl Welch {
+    /// Test sample `b` against sample `a`, if both have at least 2 values.
+    #[must_use]
+    pub fn new(a: &[f64], b: &[f64]) -> Option<Self> {
+        let (mean_a, var_a) = mean_variance(a)?;
+        let (mean_b, var_b) = mean_variance(b)?;
+        let difference = mean_b - mean_a;
+        let (se_a, se_b) = (var_a / count(a.len()), var_b / count(b.len()));
+        let error = (se_a + se_b).sqrt();
+
+        // constant samples, like compressed sizes, differ exactly
+        if error == 0.0 {
+            let p_value = if difference == 0.0 { 1.0 } else { 0.0 };
+            return Some(Self {
+                difference,
+                interval: (difference, difference),
+                p_value,
+            });
+        }
+
+        let freedom =
+            (se_a + se_b).powi(2) / (se_a.powi(2) / (count(a.len()) - 1.0) + se_b.powi(2) / (count(b.len()) - 1.0));
+        let p_value = t_two_sided(difference / error, freedom);
+        let margin = t_critical(ALPHA, freedom) * error;
+        Some(Self {
+            difference,
+            interval: (difference - margin, difference + margin),
+            p_value,
+        })
+    }
+}
+
+/// Mann-Whitney U test between two samples.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MannWhitney {
+    /// U statistic of the first sample.
+    pub u: f64,
+    /// Two-sided p-value, exact for small samples without ties.
+    pub p_value: f64,
+}
+
+impl MannWhitney {
+    /// Test sample `b` against sample `a`, if both have at least 2 values.
+    #[must_use]
+    pub fn new(first: &[f64], second: &[f64]) -> Option<Self> {
+        if first.len() < 2 || second.len() < 2 {
+            return None;
+        }
+
+        let mut values: Vec<_> = first
+            .iter()
+            .map(|value| (*value, true))
+            .chain(second.iter().map(|value| (*value, false)))
+            .collect();
+        values.sort_unstable_by(|x, y| x.0.total_cmp(&y.0));
+
+        // average ranks of tied values
+        let (mut rank_sum, mut ties, mut rank) = (0.0, 0.0, 0.0);
+        for group in values.chunk_by(|x, y| x.0.total_cmp(&y.0).is_eq()) {
+            let size = count(group.len());
+            let average = (size + 1.0).mul_add(0.5, rank);
+            rank += size;
+            ties += size.powi(3) - size;
+            rank_sum += average * count(group.iter().filter(|(_, is_first)| *is_first).count());
+        }
+
+        let (n_first, n_second) = (count(first.len()), count(second.len()));
+        let pairs = n_first * n_second;
+        let u = (-n_first * (n_first + 1.0)).mul_add(0.5, rank_sum);
+        let p_value = if ties == 0.0 && first.len() + second.len() <= MAX_EXACT_SAMPLES {
+            exact_u_p_value(first.len(), second.len(), u.min(pairs - u))
+        } else {
+            let total = n_first + n_second;
+            let variance = pairs / 12.0 * ((total + 1.0) - ties / (total * (total - 1.0)));
+            if variance > 0.0 {
+                let z_score = ((pairs.mul_add(-0.5, u)).abs() - 0.5).max(0.0) / variance.sqrt();
+                erfc(z_score / std::f64::consts::SQRT_2)
+            } else {
+                1.0
+            }
+        };
+        Some(Self {
+            u,
+            p_value: p_value.min(1.0),
+        })
+    }
+}
+
+/// Quantile `numerator / denominator` of a sorted, non-empty sample, interpolating between values.
+fn quantile(sorted: &[f64], numerator: usize, denominator: usize) -> f64 {
+    let position = sorted.len().saturating_sub(1) * numerator;
+    let (index, remainder) = (position.div_euclid(denominator), position.rem_euclid(denominator));
+    let low = sorted[index];
+    let high = sorted.get(index + 1).copied().unwrap_or(low);
+    (high - low).mul_add(count(remainder) / count(denominator), low)
+}
+
+/// Sample count, for arithmetic.
+fn count(n: usize) -> f64 {
+    f64::from(u32::try_from(n).unwrap_or(u32::MAX))
+}
+
+/// Mean and unbiased variance of a sample, if it has at least 2 values.
+fn mean_variance(sample: &[f64]) -> Option<(f64, f64)> {
+    if sample.len() < 2 {
+        return None;
+    }
+    let n = count(sample.len());
+    let mean = sample.iter().sum::<f64>() / n;
+    let variance = sample.iter().map(|value| (value - mean).powi(2)).sum::<f64>() / (n - 1.0);
+    Some((mean, variance))
+}
+
+/// Two-sided p-value of the t statistic with `freedom` degrees of freedom.
+fn t_two_sided(t: f64, freedom: f64) -> f64 {
+    incomplete_beta(freedom * 0.5, 0.5, freedom / t.mul_add(t, freedom))
+}
+
+/// Value of the t statistic with a two-sided p-value of `alpha`.
+fn t_critical(alpha: f64, freedom: f64) -> f64 {
+    let (mut low, mut high) = (0.0, 1e6);
+    for _ in 0..200 {
+        let middle = (low + high) * 0.5;
+        if t_two_sided(middle, freedom) > alpha {
+            low = middle;
+        } else {
+            high = middle;
+        }
+    }
+    (low + high) * 0.5
+}
+
+/// Two-sided p-value of the smaller U statistic, from its exact distribution without ties.
+fn exact_u_p_value(n_a: usize, n_b: usize, u: f64) -> f64 {
+    // ways[j][k]: arrangements of i values of the first sample and j of the second with U = k
+    let mut ways: Vec<Vec<f64>> = vec![vec![1.0]; n_b + 1];
+    for _ in 1..=n_a {
+        let mut next: Vec<Vec<f64>> = Vec::with_capacity(n_b + 1);
+        next.push(vec![1.0]);
+        for (j, above) in ways.iter().enumerate().skip(1) {
+            // largest value from the second sample adds nothing, from the first sample adds j
+            let left = &next[j - 1];
+            let mut row = vec![0.0; left.len().max(above.len() + j)];
+            for (k, value) in left.iter().enumerate() {
+                row[k] += value;
+            }
+            for (k, value) in above.iter().enumerate() {
+                row[k + j] += value;
+            }
+            next.push(row);
+        }
+        ways = next;
+    }
+
+    let distribution = &ways[n_b];
+    let total: f64 = distribution.iter().sum();
+    let tail: f64 = distribution
+        .iter()
+        .zip(0..)
+        .take_while(|&(_, k)| f64::from(k) <= u)
+        .map(|(value, _)| value)
+        .sum();
+    2.0 * tail / total
+}
+
+/// Regularized incomplete beta function `I_x(a, b)`.
+fn incomplete_beta(a: f64, b: f64, x: f64) -> f64 {
+    if x <= 0.0 {
+        return 0.0;
+    }
+    if x >= 1.0 {
+        return 1.0;
+    }
+    let log_front = b.mul_add((1.0 - x).ln(), a.mul_add(x.ln(), ln_gamma(a + b) - ln_gamma(a) - ln_gamma(b)));
+    let front = log_front.exp();
+    if x < (a + 1.0) / (a + b + 2.0) {
+        front * beta_fraction(a, b, x) / a
+    } else {
+        (-front).mul_add(beta_fraction(b, a, 1.0 - x) / b, 1.0)
+    }
+}
+
+/// Continued fraction for [`incomplete_beta`], by the modified Lentz method.
+fn beta_fraction(a: f64, b: f64, x: f64) -> f64 {
+    const TINY: f64 = 1e-300;
+    let clamp = |value: f64| if value.abs() < TINY { TINY } else { value };
+
+    let mut numerator = 1.0;
+    let mut denominator = 1.0 / clamp(1.0 - (a + b) * x / (a + 1.0));
+    let mut fraction = denominator;
+    let mut step = 1.0;
+    for _ in 0..300 {
+        let double = 2.0 * step;
+        let even = step * (b - step) * x / ((a + double - 1.0) * (a + double));
+        denominator = 1.0 / clamp(even.mul_add(denominator, 1.0));
+        numerator = clamp(1.0 + even / numerator);
+        fraction *= denominator * numerator;
+
+        let odd = -(a + step) * (a + b + step) * x / ((a + double) * (a + double + 1.0));
+        denominator = 1.0 / clamp(odd.mul_add(denominator, 1.0));
+        numerator = clamp(1.0 + odd / numerator);
+        let change = denominator * numerator;
+        fraction *= change;
+        if (change - 1.0).abs() < 1e-15 {
+            break;
+        }
+        step += 1.0;
+    }
+    fraction
+}
+
+/// Natural logarithm of the gamma function, by the Lanczos approximation.
+fn ln_gamma(x: f64) -> f64 {
+    const COEFFICIENTS: [f64; 9] = [
+        0.999_999_999_999_809_9,
+        676.520_368_121_885_1,
+        -1_259.139_216_722_402_8,
+        771.323_428_777_653_1,
+        -176.615_029_162_140_6,
+        12.507_343_278_686_905,
+        -0.138_571_095_265_720_12,
+        9.984_369_578_019_572e-6,
+        1.505_632_735_149_311_6e-7,
+    ];
+    if x < 0.5 {
+        let pi = std::f64::consts::PI;
+        return (pi / (pi * x).sin()).ln() - ln_gamma(1.0 - x);
+    }
+    let x = x - 1.0;
+    let mut sum = COEFFICIENTS[0];
+    let mut shift = x;
+    for coefficient in &COEFFICIENTS[1..] {
+        shift += 1.0;
+        sum += coefficient / shift;
+    }
+    let t = x + 7.5;
+    0.5_f64.mul_add(std::f64::consts::TAU.ln(), (x + 0.5).mul_add(t.ln(), -t)) + sum.ln()
+}
+
+/// Complementary error function, with a relative error below `1.2e-7`.
+fn erfc(x: f64) -> f64 {
+    let z = x.abs();
+    let t = 1.0 / 0.5_f64.mul_add(z, 1.0);
+    let polynomial = [
+        -1.265_512_23,
+        1.000_023_68,
+        0.374_091_96,
+        0.096_784_18,
+        -0.186_288_06,
+        0.278_868_07,
+        -1.135_203_98,
+        1.488_515_87,
+        -0.822_152_23,
+        0.170_872_77,
+    ]
+    .iter()
+    .rev()
+    .fold(0.0, |sum: f64, coefficient| sum.mul_add(t, *coefficient));
+    let result = t * (-z).mul_add(z, polynomial).exp();
+    if x >= 0.0 { result } else { 2.0 - result }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use test_log::test;
+
+    use super::*;
+
+    /// Values are equal to 4 decimal places.
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-4, "{actual} is not close to {expected}");
+    }
+
+    #[test]
+    fn special_functions() {
+        assert_close(ln_gamma(5.0), 24.0_f64.ln());
+        assert_close(ln_gamma(0.5), std::f64::consts::PI.sqrt().ln());
+        assert_close(erfc(0.0), 1.0);
+        assert_close(erfc(1.0), 0.157_299_2);
+        assert_close(erfc(-1.0), 1.842_700_8);
+        assert_close(incomplete_beta(2.0, 3.0, 0.4), 0.5248);
+        assert_close(t_critical(0.05, 10.0), 2.228_138_9);
+        assert_close(t_two_sided(2.0, 5.0), 0.101_939_4);
+    }
+
+    #[test]
+    fn welch_t_test() {
+        // example from https://en.wikipedia.org/wiki/Welch%27s_t-test
+        let a = [
+            27.5, 21.0, 19.0, 23.6, 17.0, 17.9, 16.9, 20.1, 21.9, 22.6, 23.1, 19.6, 19.0, 21.7, 21.4,
+        ];
+        let b = [
+            27.1, 22.0, 20.8, 23.4, 23.4, 23.5, 25.8, 22.0, 24.8, 20.2, 21.9, 22.1, 22.9, 20.5, 24.4,
+        ];
+        let welch = Welch::new(&a, &b).unwrap();
+        assert_close(welch.difference, 2.166_666_7);
+        assert!((welch.p_value - 0.021).abs() < 1e-3, "{}", welch.p_value);
+        assert!(welch.interval.0 > 0.0 && welch.interval.1 < 4.0, "{:?}", welch.interval);
+
+        let constant = Welch::new(&[100.0, 100.0], &[90.0, 90.0]).unwrap();
+        assert_eq!((constant.difference, constant.p_value), (-10.0, 0.0));
+        assert_eq!(Welch::new(&[1.0], &[1.0, 2.0]), None);
+    }
+
+    #[test]
+    fn mann_whitney_u_test() {
+        let exact = MannWhitney::new(&[1.0, 2.0, 3.0], &[4.0, 5.0, 6.0]).unwrap();
+        assert_close(exact.u, 0.0);
+        assert_close(exact.p_value, 0.1);
+
+        let overlap = MannWhitney::new(&[1.1, 2.2, 3.3, 4.4], &[1.0, 2.1, 3.2, 4.3]).unwrap();
+        assert_close(overlap.u, 10.0);
+        assert_close(overlap.p_value, 0.6857);
+
+        let ties = MannWhitney::new(&[1.0, 1.0, 2.0, 2.0], &[2.0, 3.0, 3.0, 3.0]).unwrap();
+        assert!(ties.p_value > 0.02 && ties.p_value < 0.1, "{}", ties.p_value);
+        assert_close(MannWhitney::new(&[1.0, 1.0], &[1.0, 1.0]).unwrap().p_value, 1.0);
+    }
+
+    #[test]
+    fn flags_outliers() {
+        assert_close(quantile(&[1.0, 2.0, 3.0, 4.0], 1, 4), 1.75);
+        assert_close(quantile(&[1.0, 2.0, 3.0, 4.0], 1, 2), 2.5);
+        assert_close(quantile(&[5.0], 3, 4), 5.0);
+
+        let sample = [10.1, 9.9, 10.0, 10.2, 25.0, 9.8];
+        for method in [OutlierMethod::Iqr, OutlierMethod::Mad] {
+            let outliers = Outliers { method, exclude: false };
+            assert_eq!(outliers.flag(&sample), [false, false, false, false, true, false], "{method}");
+            assert_eq!(outliers.filter(&sample), sample, "{method}: kept");
+
+            let excluded = Outliers { method, exclude: true };
+            assert_eq!(excluded.filter(&sample), [10.1, 9.9, 10.0, 10.2, 9.8], "{method}: excluded");
+            assert_eq!(excluded.flag(&[1.0, 100.0]), [false, false], "{method}: too small");
+            assert_eq!(excluded.flag(&[4.0, 4.0, 4.0, 9.0]), [false, false, false, method == OutlierMethod::Iqr]);
+        }
+    }
+
+    #[test]
+    fn marks_significance() {
+        let fast = [10.0, 10.2, 9.9, 10.1, 10.0];
+        let slow = [12.0, 12.1, 11.9, 12.2, 12.0];
+        let noisy = [9.0, 11.5, 10.2, 8.7, 11.0];
+        for test in [Test::Welch, Test::MannWhitney] {
+            assert!(Comparison::new(test, &fast, &slow).unwrap().is_significant(), "{test}: clear difference");
+            assert!(!Comparison::new(test, &fast, &noisy).unwrap().is_significant(), "{test}: within noise");
+        }
+        assert_eq!(Comparison::new(Test::Welch, &fast, &[]), None);
+
+        let (first, second) = ([1.0, 2.0, 3.0, 4.0], [5.0, 6.0, 7.0, 8.0]);
+        assert!(
+            Comparison::new(Test::MannWhitney, &first, &second)
+                .unwrap()
+                .is_significant(),
+            "4 values"
+        );
+        assert!(MannWhitney::new(&first[..3], &second[..3]).unwrap().p_value >= ALPHA, "3 values");
+        assert_eq!(Comparison::new(Test::MannWhitney, &first[..3], &second[..3]), None, "can't be significant");
+    }
+}
@@ -28,7 +28,8 @@ const SHARED_ENVS: &[&str] = &[
 pub fn run0(program: impl IntoIterator<Item = impl Into<Vec<u8>>>) -> Result<Infallible> {
     let binary = c"/usr/bin/run0";
 
-    let mut args = vec![binary.to_owned()];
+    // lets the program enable cgroup controllers inside its own unit
+    let mut args = vec![binary.to_owned(), c"--property=Delegate=yes".to_owned()];
     for &env in SHARED_ENVS {
         if std::env::var_os(env).is_some() {
             log::trace!("run0: using env {env:?}");
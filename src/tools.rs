@@ -0,0 +1,191 @@
+//! Discovery of external binaries.
+
+use std::fmt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{Result, bail};
+use hashbrown::HashMap;
+
+use crate::utils::command;
+use crate::utils::strings::{self, utf8_lossy};
+
+/// Search path used when `PATH` is not set.
+const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/bin";
+
+/// Binary path set by the user, in the format `NAME=PATH`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ToolPath {
+    /// Name of the tool, as used by the compression methods.
+    pub name: Box<str>,
+    /// Path to the binary.
+    pub path: PathBuf,
+}
+
+impl FromStr for ToolPath {
+    type Err = anyhow::Error;
+
+    fn from_str(spec: &str) -> Result<Self> {
+        let Some((name, path)) = spec.split_once('=') else {
+            bail!("expected NAME=PATH: {spec:?}");
+        };
+        if name.trim().is_empty() || path.is_empty() {
+            bail!("expected NAME=PATH: {spec:?}");
+        }
+        Ok(Self {
+            name: name.trim().into(),
+            path: path.into(),
+        })
+    }
+}
+
+/// An external binary resolved to a path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tool {
+    /// Name of the tool, as used by the compression methods.
+    pub name: Box<str>,
+    /// Resolved path to the binary.
+    pub path: PathBuf,
+    /// First line of `--version` output, if available.
+    pub version: Option<Box<str>>,
+}
+
+impl fmt::Display for Tool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.path.display())?;
+        if let Some(version) = &self.version {
+            write!(f, " ({version})")?;
+        }
+        Ok(())
+    }
+}
+
+/// Binaries resolved at startup, by name.
+#[derive(Debug, Clone, Default)]
+pub struct Tools {
+    /// Either the resolved binary or a reason why it is missing.
+    resolved: HashMap<Box<str>, Result<Tool, Box<str>>>,
+}
+
+impl Tools {
+    /// Resolve all `names`, using the paths in `overrides` or searching `PATH`.
+    ///
+    /// Missing binaries are kept, so their methods can be reported as skipped.
+    pub fn discover<'a>(names: impl IntoIterator<Item = &'a str>, overrides: &[ToolPath]) -> Self {
+        let search_path = std::env::var_os("PATH").unwrap_or_else(|| DEFAULT_PATH.into());
+
+        let resolved = names
+            .into_iter()
+            .map(|name| {
+                let configured = overrides.iter().rev().find(|tool| *tool.name == *name);
+                let path = match configured {
+                    Some(tool) if is_executable(&tool.path) => Ok(tool.path.clone()),
+                    Some(tool) => Err(format!("{} is not executable", tool.path.display()).into()),
+                    None => std::env::split_paths(&search_path)
+                        .map(|dir| dir.join(name))
+                        .find(|path| is_executable(path))
+                        .ok_or_else(|| "not found in PATH".into()),
+                };
+
+                let tool = path.map(|path| Tool {
+                    name: name.into(),
+                    version: version(&path),
+                    path,
+                });
+                match &tool {
+                    Ok(tool) => log::info!("tool {name}: {tool}"),
+                    Err(reason) => log::warn!("tool {name}: {reason}"),
+                }
+                (name.into(), tool)
+            })
+            .collect();
+
+        Self { resolved }
+    }
+
+    /// All resolved binaries, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = &Tool> {
+        self.resolved.values().filter_map(|tool| tool.as_ref().ok())
+    }
+
+    /// Get a resolved binary, or the reason why it is missing.
+    ///
+    /// # Errors
+    ///
+    /// Binary not found, or not searched by [`Tools::discover`].
+    pub fn get(&self, name: &str) -> Result<&Tool, &str> {
+        match self.resolved.get(name) {
+            Some(Ok(tool)) => Ok(tool),
+            Some(Err(reason)) => Err(reason),
+            None => Err("not discovered"),
+        }
+    }
+}
+
+/// Path is a regular file with any execute permission bit set.
+fn is_executable(path: &Path) -> bool {
+    path.metadata()
+        .is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+}
+
+/// Get the first line of `program --version`.
+#[must_use]
+pub fn version(program: &Path) -> Option<Box<str>> {
+    let output = match command::command(program, ["--version"]).output() {
+        Ok(output) if output.status.success() => output,
+        Ok(output) => {
+            log::debug!("version: program={}, status={}", program.display(), output.status);
+            return None;
+        }
+        Err(error) => {
+            log::debug!("version: program={}, error={error}", program.display());
+            return None;
+        }
+    };
+
+    let mut lines = strings::lines(&output.stdout).chain(strings::lines(&output.stderr));
+    lines.find(|line| !line.trim_ascii().is_empty()).map(|line| {
+        let line = utf8_lossy(line.trim_ascii()).to_string();
+        line.trim_matches(|ch: char| ch == '*' || ch.is_whitespace()).into()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use test_log::test;
+
+    use super::*;
+
+    #[test]
+    fn parses_tool_path() {
+        let tool: ToolPath = "zstdmt=/opt/zstd/bin/zstdmt".parse().unwrap();
+        assert_eq!(&*tool.name, "zstdmt");
+        assert_eq!(tool.path, Path::new("/opt/zstd/bin/zstdmt"));
+
+        let error = "zstdmt".parse::<ToolPath>().unwrap_err();
+        assert_eq!(error.to_string(), "expected NAME=PATH: \"zstdmt\"");
+
+        let error = "=/usr/bin/lz4".parse::<ToolPath>().unwrap_err();
+        assert_eq!(error.to_string(), "expected NAME=PATH: \"=/usr/bin/lz4\"");
+    }
+
+    #[test]
+    fn discovers_in_path_and_overrides() {
+        let overrides = [
+            "custom=/usr/bin/env".parse().unwrap(),
+            "broken=/nonexistent/bin/tool".parse().unwrap(),
+        ];
+        let tools = Tools::discover(["bash", "custom", "broken", "definitely-not-a-real-tool"], &overrides);
+
+        let bash = tools.get("bash").unwrap();
+        assert!(bash.path.ends_with("bash"), "found in PATH");
+        assert!(bash.version.as_ref().is_some_and(|version| version.contains("bash")), "has version");
+
+        assert_eq!(tools.get("custom").unwrap().path, Path::new("/usr/bin/env"));
+        assert_eq!(tools.get("broken").unwrap_err(), "/nonexistent/bin/tool is not executable");
+        assert_eq!(tools.get("definitely-not-a-real-tool").unwrap_err(), "not found in PATH");
+        assert_eq!(tools.get("never-searched").unwrap_err(), "not discovered");
+    }
+}
@@ -1,9 +1,11 @@
 //! Utilities for process execution.
 
+use std::error::Error;
 use std::ffi::OsStr;
-use std::process::{Command, Output, Stdio};
+use std::fmt;
+use std::process::{Command, ExitStatus, Output, Stdio};
 
-use anyhow::{Result, bail};
+use anyhow::Result;
 
 use crate::utils::strings::utf8_lossy;
 
@@ -29,25 +31,61 @@ pub fn command(program: impl AsRef<OsStr>, args: impl IntoIterator<Item = impl A
     cmd
 }
 
+/// Maximum number of lines from stderr kept in a [`CommandError`] or [`SignalError`](crate::measure::SignalError).
+const STDERR_EXCERPT_LINES: usize = 20;
+
+/// A command that exited with failure status.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandError {
+    /// Name of the command.
+    pub name: String,
+    /// Exit status of the command.
+    pub status: ExitStatus,
+    /// Last lines from stderr, trimmed.
+    pub stderr: String,
+}
+
+impl CommandError {
+    /// Build error from command output, keeping only the end of stderr.
+    fn new(name: &str, output: &Output) -> Self {
+        Self {
+            name: name.into(),
+            status: output.status,
+            stderr: stderr_excerpt(&output.stderr),
+        }
+    }
+}
+
+/// Last lines from `stderr`, trimmed, for error messages.
+#[must_use]
+pub fn stderr_excerpt(stderr: &[u8]) -> String {
+    let lines: Vec<_> = strings::lines(stderr).collect();
+    let excerpt = &lines[lines.len().saturating_sub(STDERR_EXCERPT_LINES)..];
+    String::from_utf8_lossy(&excerpt.join(&b'\n')).trim().into()
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.status.code(), self.stderr.is_empty()) {
+            (Some(code), false) => write!(f, "{} failed (status = {code}): {}", self.name, self.stderr),
+            (Some(code), true) => write!(f, "{} failed (status = {code})", self.name),
+            (None, false) => write!(f, "{} failed: {}", self.name, self.stderr),
+            (None, true) => write!(f, "{} failed", self.name),
+        }
+    }
+}
+
+impl Error for CommandError {}
+
 /// Verify command output.
 ///
 /// Check exit status, stderr and (optionally) stdout.
+///
+/// # Errors
+///
+/// Non-zero exit status, as a [`CommandError`].
 pub fn check(name: &str, output: Output, show_stdout: bool) -> Result<Vec<u8>> {
-    log::trace!(
-        "{name}: exit={}, #lines stdout={}, #lines stderr={}",
-        output.status,
-        strings::lines(&output.stdout).count(),
-        strings::lines(&output.stderr).count()
-    );
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        match (output.status.code(), stderr.trim().is_empty()) {
-            (Some(code), false) => bail!("{name} failed (status = {code}): {stderr}"),
-            (Some(code), true) => bail!("{name} failed (status = {code})"),
-            (None, false) => bail!("{name} failed: {stderr}"),
-            (None, true) => bail!("{name} failed"),
-        }
-    }
+    check_status(name, &output)?;
     for line in strings::lines(&output.stderr) {
         log::warn!("{name}: {}", utf8_lossy(line));
     }
@@ -59,6 +97,24 @@ pub fn check(name: &str, output: Output, show_stdout: bool) -> Result<Vec<u8>> {
     Ok(output.stdout)
 }
 
+/// Verify exit status only, for output that was already logged.
+///
+/// # Errors
+///
+/// Non-zero exit status, as a [`CommandError`].
+pub fn check_status(name: &str, output: &Output) -> Result<()> {
+    log::trace!(
+        "{name}: exit={}, #lines stdout={}, #lines stderr={}",
+        output.status,
+        strings::lines(&output.stdout).count(),
+        strings::lines(&output.stderr).count()
+    );
+    if !output.status.success() {
+        return Err(CommandError::new(name, output).into());
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use std::os::unix::process::ExitStatusExt;
@@ -96,4 +152,17 @@ mod tests {
         let err = check("fifth", output!(0x0018, b"", b"   "), true).unwrap_err();
         assert_eq!(err.to_string(), "fifth failed");
     }
+
+    #[test]
+    fn error_keeps_details() {
+        let stderr: Vec<_> = (1..=30).flat_map(|idx| format!("line {idx}\n").into_bytes()).collect();
+        let err = check("sixth", output!(0x0200, b"", stderr), false).unwrap_err();
+
+        let error = err.downcast_ref::<CommandError>().unwrap();
+        assert_eq!(error.name, "sixth");
+        assert_eq!(error.status.code(), Some(2));
+        assert_eq!(error.stderr.lines().count(), STDERR_EXCERPT_LINES);
+        assert_eq!(error.stderr.lines().next(), Some("line 11"));
+        assert_eq!(error.stderr.lines().last(), Some("line 30"));
+    }
 }
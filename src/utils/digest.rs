@@ -0,0 +1,91 @@
+//! Hashing file contents.
+
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use sha2::{Digest as _, Sha256};
+
+/// SHA-256 digest of some content.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(transparent)]
+pub struct Digest([u8; 32]);
+
+impl Digest {
+    /// Hash the full contents of a file.
+    ///
+    /// # Errors
+    ///
+    /// File cannot be opened or read.
+    pub fn of_file(path: &Path) -> Result<Self> {
+        let mut file = File::open(path).with_context(|| format!("could not open {}", path.display()))?;
+        let mut hasher = Sha256::new();
+        let size = io::copy(&mut file, &mut hasher).with_context(|| format!("could not read {}", path.display()))?;
+
+        let digest = Self(hasher.finalize().into());
+        log::trace!("Digest::of_file: path={}, size={size}, digest={digest}", path.display());
+        Ok(digest)
+    }
+
+    #[cfg(test)]
+    /// Hash a byte string.
+    #[must_use]
+    pub fn of_bytes(bytes: impl AsRef<[u8]>) -> Self {
+        Self(Sha256::digest(bytes).into())
+    }
+}
+
+impl fmt::Debug for Digest {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Digest({self})")
+    }
+}
+
+/// Lowercase hexadecimal, same as `sha256sum`.
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in self.0 {
+            write!(f, "{byte:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use pretty_assertions::assert_eq;
+    use tempfile::NamedTempFile;
+    use test_log::test;
+
+    use super::*;
+
+    #[test]
+    fn matches_sha256sum() {
+        assert_eq!(
+            Digest::of_bytes(b"").to_string(),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            Digest::of_bytes(b"abc").to_string(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn file_and_bytes_agree() {
+        let mut tmp = NamedTempFile::new().unwrap();
+        tmp.write_all(b"some file contents\n").unwrap();
+
+        let digest = Digest::of_file(tmp.path()).unwrap();
+        assert_eq!(digest, Digest::of_bytes(b"some file contents\n"));
+        assert_ne!(digest, Digest::of_bytes(b"other contents\n"));
+
+        let error = Digest::of_file("/nonexistent/file".as_ref()).unwrap_err();
+        assert_eq!(error.to_string(), "could not open /nonexistent/file");
+    }
+}
@@ -1,4 +1,5 @@
 //! Utilities.
 
 pub mod command;
+pub mod digest;
 pub mod strings;
@@ -0,0 +1,405 @@
+//! Directory where compression and decompression read and write their files.
+
+use std::ffi::{CString, OsString};
+use std::fmt;
+use std::fs::File;
+use std::os::fd::AsRawFd;
+use std::os::unix::ffi::OsStringExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{Context, Result, bail};
+use nix::mount::{MntFlags, MsFlags, mount, umount2};
+use nix::sched::{CloneFlags, unshare};
+use nix::sys::memfd::{MemFdCreateFlag, memfd_create};
+use nix::sys::statfs::{TMPFS_MAGIC, statfs};
+use tempfile::TempDir;
+
+use crate::compression::{Compression, Threads};
+use crate::measure::{Options, Stats};
+use crate::tools::Tool;
+
+/// Prefix for directories created inside the work directory.
+const TEMP_PREFIX: &str = "mkinitcpio-compression-benchmark-";
+
+/// Work directory chosen by the user.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WorkdirSpec {
+    /// A tmpfs mounted on a temporary directory, in a private mount namespace.
+    Tmpfs,
+    /// Anonymous memory files, only reachable through `/proc/self/fd`.
+    ///
+    /// Tools read from stdin and write to stdout, since they can't name their output after the input.
+    Memfd,
+    /// A temporary directory inside an existing directory.
+    Dir(PathBuf),
+}
+
+impl WorkdirSpec {
+    /// Make a directory path absolute, so it survives changes of current directory.
+    ///
+    /// # Errors
+    ///
+    /// Current directory not available.
+    pub fn absolute(&self) -> Result<Self> {
+        match self {
+            Self::Tmpfs => Ok(Self::Tmpfs),
+            Self::Memfd => Ok(Self::Memfd),
+            Self::Dir(path) => Ok(Self::Dir(std::path::absolute(path)?)),
+        }
+    }
+}
+
+impl FromStr for WorkdirSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(spec: &str) -> Result<Self> {
+        match spec {
+            "" => bail!("expected a directory, `tmpfs` or `memfd`"),
+            "tmpfs" => Ok(Self::Tmpfs),
+            "memfd" => Ok(Self::Memfd),
+            path => Ok(Self::Dir(path.into())),
+        }
+    }
+}
+
+impl fmt::Display for WorkdirSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tmpfs => f.write_str("tmpfs"),
+            Self::Memfd => f.write_str("memfd"),
+            Self::Dir(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// Work directory for a run, removed on drop.
+///
+/// Without a [`WorkdirSpec`], files are kept next to the images in the output directory.
+#[derive(Debug)]
+pub struct Workdir {
+    /// Where work files are placed.
+    root: PathBuf,
+    /// Output directory, mirrored inside the work directory.
+    outdir: PathBuf,
+    /// Temporary directory, if one was created.
+    temp: Option<TempDir>,
+    /// A tmpfs is mounted on `root`.
+    mounted: bool,
+    /// Work files are memfds instead of files under `root`.
+    memfd: bool,
+}
+
+impl Workdir {
+    /// Prepare the work directory for images under `outdir`.
+    ///
+    /// # Errors
+    ///
+    /// Could not create the directory or mount the tmpfs.
+    pub fn new(spec: Option<&WorkdirSpec>, outdir: &Path) -> Result<Self> {
+        let temp = match spec {
+            None | Some(WorkdirSpec::Memfd) => None,
+            Some(WorkdirSpec::Tmpfs) => Some(tempfile::Builder::new().prefix(TEMP_PREFIX).tempdir()?),
+            Some(WorkdirSpec::Dir(dir)) => Some(
+                tempfile::Builder::new()
+                    .prefix(TEMP_PREFIX)
+                    .tempdir_in(dir)
+                    .with_context(|| format!("could not create work directory in {}", dir.display()))?,
+            ),
+        };
+        let mut workdir = Self {
+            root: temp.as_ref().map_or(outdir, TempDir::path).to_path_buf(),
+            outdir: outdir.to_path_buf(),
+            temp,
+            mounted: false,
+            memfd: spec == Some(&WorkdirSpec::Memfd),
+        };
+
+        if spec == Some(&WorkdirSpec::Tmpfs) {
+            // the mount goes away with the namespace, even if the process is killed
+            unshare_mounts()?;
+            let flags = MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC;
+            mount(Some("tmpfs"), &workdir.root, Some("tmpfs"), flags, Some("mode=0700"))
+                .with_context(|| format!("could not mount tmpfs on {}", workdir.root.display()))?;
+            workdir.mounted = true;
+        }
+        if workdir.memfd {
+            log::info!("workdir: memfd");
+        } else {
+            log::info!("workdir: {}", workdir.root.display());
+        }
+        Ok(workdir)
+    }
+
+    /// Work files live in memory, where the page cache can't be dropped: memfds or a tmpfs.
+    ///
+    /// # Errors
+    ///
+    /// Could not get the filesystem of the directory.
+    pub fn is_in_memory(&self) -> Result<bool> {
+        if self.memfd || self.mounted {
+            return Ok(true);
+        }
+        let filesystem = statfs(&self.root)
+            .with_context(|| format!("could not get the filesystem of {}", self.root.display()))?
+            .filesystem_type();
+        Ok(filesystem == TMPFS_MAGIC)
+    }
+
+    /// Work files for `file`, an image under the output directory, and its compressed version.
+    ///
+    /// # Errors
+    ///
+    /// Could not create parent directories or memfds.
+    pub fn files_for(&self, file: &Path, extension: &str) -> Result<WorkFiles> {
+        if !self.memfd {
+            let image = self.path_for(file)?;
+            let mut compressed = image.clone().into_os_string();
+            compressed.push(extension);
+            return Ok(WorkFiles {
+                image,
+                compressed: compressed.into(),
+                memfds: None,
+            });
+        }
+
+        let name = file.file_name().unwrap_or_default().to_owned();
+        let mut compressed_name = name.clone();
+        compressed_name.push(extension);
+        let image = memfd(name)?;
+        let compressed = memfd(compressed_name)?;
+        Ok(WorkFiles {
+            image: fd_path(&image),
+            compressed: fd_path(&compressed),
+            memfds: Some([image, compressed]),
+        })
+    }
+
+    /// Path for a working copy of `file`, an image under the output directory.
+    ///
+    /// Same layout as the output directory, creating parent directories if necessary.
+    ///
+    /// # Errors
+    ///
+    /// Could not create parent directories.
+    pub fn path_for(&self, file: &Path) -> Result<PathBuf> {
+        let relative = file
+            .strip_prefix(&self.outdir)
+            .ok()
+            .or_else(|| file.file_name().map(Path::new))
+            .with_context(|| format!("not a file: {}", file.display()))?;
+        let path = self.root.join(relative);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).with_context(|| format!("could not create {}", parent.display()))?;
+        }
+        Ok(path)
+    }
+
+    /// Move the compressed image to `destination` in the output directory, removing the other work files.
+    ///
+    /// Nothing changes if the work directory is the output directory.
+    ///
+    /// # Errors
+    ///
+    /// Could not copy or remove the files.
+    pub fn collect(&self, files: &WorkFiles, destination: &Path) -> Result<()> {
+        if self.temp.is_none() && !self.memfd {
+            return Ok(());
+        }
+
+        let compressed_image = &files.compressed;
+        let written = std::fs::metadata(compressed_image).is_ok_and(|metadata| metadata.len() > 0);
+        if written {
+            std::fs::copy(compressed_image, destination).with_context(|| {
+                format!("could not copy {} to {}", compressed_image.display(), destination.display())
+            })?;
+        }
+        if files.memfds.is_some() {
+            // memory is freed once the work files are dropped
+            return Ok(());
+        }
+        for file in [compressed_image, &files.image] {
+            if let Err(error) = std::fs::remove_file(file)
+                && error.kind() != std::io::ErrorKind::NotFound
+            {
+                return Err(error).with_context(|| format!("could not remove {}", file.display()));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Image and compressed image of a job, in the work directory.
+#[derive(Debug)]
+pub struct WorkFiles {
+    /// Copy of the raw image, then the decompressed image.
+    pub image: PathBuf,
+    /// Compressed image.
+    pub compressed: PathBuf,
+    /// Memfds behind `image` and `compressed`, kept open so the paths stay valid.
+    memfds: Option<[File; 2]>,
+}
+
+impl WorkFiles {
+    /// Compress the image, keeping it.
+    ///
+    /// # Errors
+    ///
+    /// Command failed to run or exited with non-zero status.
+    pub fn compress(
+        &self,
+        compression: &Compression,
+        tool: &Tool,
+        threads: Option<Threads>,
+        options: &Options,
+    ) -> Result<Stats> {
+        if self.memfds.is_some() {
+            compression.compress_stream(tool, &self.image, &self.compressed, threads, options)
+        } else {
+            compression.compress(tool, &self.image, threads, options)
+        }
+    }
+
+    /// Decompress the compressed image back to the image, keeping the compressed image.
+    ///
+    /// # Errors
+    ///
+    /// Command failed to run or exited with non-zero status.
+    pub fn decompress(&self, compression: &Compression, tool: &Tool, options: &Options) -> Result<Stats> {
+        if self.memfds.is_some() {
+            compression.decompress_stream(tool, &self.compressed, &self.image, options)
+        } else {
+            compression.decompress(tool, &self.compressed, options)
+        }
+    }
+
+    /// Remove the image, before it is decompressed again.
+    ///
+    /// # Errors
+    ///
+    /// Could not remove or truncate the image.
+    pub fn clear_image(&self) -> Result<()> {
+        match &self.memfds {
+            Some([image, _]) => image.set_len(0),
+            None => std::fs::remove_file(&self.image),
+        }
+        .with_context(|| format!("could not remove {}", self.image.display()))
+    }
+}
+
+impl Drop for Workdir {
+    fn drop(&mut self) {
+        if self.mounted
+            && let Err(errno) = umount2(&self.root, MntFlags::MNT_DETACH)
+        {
+            log::warn!("workdir: could not unmount {}: {errno}", self.root.display());
+        }
+    }
+}
+
+/// Create an empty memfd, named for `/proc/self/fd` listings.
+fn memfd(name: OsString) -> Result<File> {
+    let name = CString::new(name.into_vec()).context("invalid memfd name")?;
+    let fd = memfd_create(&name, MemFdCreateFlag::MFD_CLOEXEC)
+        .with_context(|| format!("could not create memfd {name:?}"))?;
+    Ok(fd.into())
+}
+
+/// Path of an open file through `/proc/self/fd`, only valid in this process.
+fn fd_path(file: &File) -> PathBuf {
+    format!("/proc/self/fd/{}", file.as_raw_fd()).into()
+}
+
+/// Move the calling thread to a private mount namespace, so its mounts don't propagate to the host.
+///
+/// Threads and processes started afterwards by this thread share the namespace.
+fn unshare_mounts() -> Result<()> {
+    unshare(CloneFlags::CLONE_NEWNS).context("could not create a private mount namespace")?;
+    mount(None::<&str>, "/", None::<&str>, MsFlags::MS_REC | MsFlags::MS_SLAVE, None::<&str>)
+        .context("could not stop mount propagation to the host")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use test_log::test;
+
+    use super::*;
+
+    #[test]
+    fn parses_spec() {
+        assert_eq!("tmpfs".parse::<WorkdirSpec>().unwrap(), WorkdirSpec::Tmpfs);
+        assert_eq!("/var/tmp".parse::<WorkdirSpec>().unwrap(), WorkdirSpec::Dir("/var/tmp".into()));
+        assert_eq!("memfd".parse::<WorkdirSpec>().unwrap(), WorkdirSpec::Memfd);
+        assert_eq!("".parse::<WorkdirSpec>().unwrap_err().to_string(), "expected a directory, `tmpfs` or `memfd`");
+    }
+
+    #[test]
+    fn mirrors_outdir() {
+        let outdir = tempfile::tempdir().unwrap();
+        let image = outdir.path().join("linux/default/test.img");
+
+        let workdir = Workdir::new(None, outdir.path()).unwrap();
+        assert_eq!(workdir.path_for(&image).unwrap(), image);
+
+        let dir = tempfile::tempdir().unwrap();
+        let workdir = Workdir::new(Some(&WorkdirSpec::Dir(dir.path().into())), outdir.path()).unwrap();
+        let files = workdir.files_for(&image, ".zst").unwrap();
+        assert!(files.image.starts_with(dir.path()), "inside work directory");
+        assert!(files.image.ends_with("linux/default/test.img"), "same layout");
+        assert!(files.compressed.ends_with("linux/default/test.img.zst"), "same layout");
+
+        let (work_image, compressed_image) = (files.image.clone(), files.compressed.clone());
+        std::fs::write(&work_image, "roundtrip").unwrap();
+        std::fs::write(&compressed_image, "compressed").unwrap();
+        let destination = outdir.path().join("test.img.zst");
+        workdir.collect(&files, &destination).unwrap();
+
+        assert_eq!(std::fs::read_to_string(&destination).unwrap(), "compressed");
+        assert!(!work_image.exists(), "work files removed");
+        assert!(!compressed_image.exists(), "work files removed");
+
+        let root = workdir.root.clone();
+        drop(workdir);
+        assert!(!root.exists(), "temporary directory removed");
+    }
+
+    #[test]
+    fn uses_memfds() {
+        let outdir = tempfile::tempdir().unwrap();
+        let workdir = Workdir::new(Some(&WorkdirSpec::Memfd), outdir.path()).unwrap();
+        let files = workdir.files_for(&outdir.path().join("test.img"), ".zst").unwrap();
+        assert!(files.image.starts_with("/proc/self/fd"), "memfd");
+        assert!(workdir.is_in_memory().unwrap(), "no cold cache");
+        assert_eq!(std::fs::read_link(&files.compressed).unwrap().to_string_lossy(), "/memfd:test.img.zst (deleted)");
+
+        std::fs::write(&files.image, "roundtrip").unwrap();
+        files.clear_image().unwrap();
+        assert_eq!(std::fs::read(&files.image).unwrap(), b"", "truncated");
+
+        std::fs::write(&files.compressed, "compressed").unwrap();
+        let destination = outdir.path().join("test.img.zst");
+        workdir.collect(&files, &destination).unwrap();
+        assert_eq!(std::fs::read_to_string(&destination).unwrap(), "compressed");
+    }
+
+    #[test]
+    #[ignore = "needs CAP_SYS_ADMIN"]
+    fn mounts_tmpfs() {
+        let outdir = tempfile::tempdir().unwrap();
+        let workdir = Workdir::new(Some(&WorkdirSpec::Tmpfs), outdir.path()).unwrap();
+        let root = workdir.root.clone();
+
+        let mounts = std::fs::read_to_string("/proc/thread-self/mounts").unwrap();
+        assert!(mounts.contains(&format!("tmpfs {} tmpfs", root.display())), "tmpfs mounted");
+        let host = std::fs::read_to_string("/proc/1/mounts").unwrap();
+        assert!(!host.contains(&format!("{} ", root.display())), "private to this thread");
+        assert!(workdir.is_in_memory().unwrap(), "no cold cache");
+
+        drop(workdir);
+        let mounts = std::fs::read_to_string("/proc/thread-self/mounts").unwrap();
+        assert!(!mounts.contains(&format!("{} ", root.display())), "tmpfs unmounted");
+        assert!(!root.exists(), "temporary directory removed");
+    }
+}